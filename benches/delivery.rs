@@ -0,0 +1,90 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Compares delivering a large message by cloning it into every subscription against sharing a single `Arc<M>`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{executor::block_on, SinkExt, StreamExt};
+use tari_pubsub::{pubsub_channel, TopicPayload};
+
+const NUM_MESSAGES: usize = 100;
+const MESSAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+struct Block(Vec<u8>);
+
+fn cloned(num_subscribers: usize) {
+    let (mut publisher, subscriber_factory) = pubsub_channel(NUM_MESSAGES);
+    let subscriptions = (0..num_subscribers)
+        .map(|_| subscriber_factory.get_subscription("Block"))
+        .collect::<Vec<_>>();
+
+    block_on(async move {
+        for _ in 0..NUM_MESSAGES {
+            publisher
+                .send(TopicPayload::new("Block", Block(vec![0; MESSAGE_SIZE])))
+                .await
+                .unwrap();
+        }
+        drop(publisher);
+        for subscription in subscriptions {
+            assert_eq!(subscription.map(|b| b.0.len()).count().await, NUM_MESSAGES);
+        }
+    });
+}
+
+fn shared(num_subscribers: usize) {
+    let (mut publisher, subscriber_factory) = pubsub_channel(NUM_MESSAGES);
+    let subscriptions = (0..num_subscribers)
+        .map(|_| subscriber_factory.get_arc_subscription("Block"))
+        .collect::<Vec<_>>();
+
+    block_on(async move {
+        for _ in 0..NUM_MESSAGES {
+            publisher
+                .send(TopicPayload::new("Block", Block(vec![0; MESSAGE_SIZE])))
+                .await
+                .unwrap();
+        }
+        drop(publisher);
+        for subscription in subscriptions {
+            assert_eq!(subscription.map(|b| b.0.len()).count().await, NUM_MESSAGES);
+        }
+    });
+}
+
+fn delivery(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delivery");
+    group.throughput(Throughput::Elements(NUM_MESSAGES as u64));
+    for num_subscribers in &[1, 10] {
+        group.bench_with_input(BenchmarkId::new("cloned", num_subscribers), num_subscribers, |b, &n| {
+            b.iter(|| cloned(n))
+        });
+        group.bench_with_input(BenchmarkId::new("shared", num_subscribers), num_subscribers, |b, &n| {
+            b.iter(|| shared(n))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, delivery);
+criterion_main!(benches);
@@ -0,0 +1,97 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Compares topic-indexed routing against the previous approach of filtering a shared `tari_broadcast_channel` in
+//! every subscription. Messages are published round-robin across distinct topics, each with a single subscriber.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{executor::block_on, future, SinkExt, Stream, StreamExt};
+use tari_broadcast_channel::{bounded, Publisher, Subscriber};
+use tari_pubsub::{pubsub_channel, TopicPayload};
+
+const NUM_MESSAGES: usize = 1_000;
+
+/// The subscription implementation used before topic-indexed routing was introduced
+fn filtered_subscription(
+    subscriber: &Subscriber<TopicPayload<usize, usize>>,
+    topic: usize,
+) -> impl Stream<Item = usize> {
+    subscriber.clone().filter_map(move |item| {
+        let result = if item.topic() == &topic {
+            Some(*item.message())
+        } else {
+            None
+        };
+        future::ready(result)
+    })
+}
+
+fn broadcast_filter(num_topics: usize) {
+    let (mut publisher, subscriber): (Publisher<TopicPayload<usize, usize>>, _) = bounded(NUM_MESSAGES, 1);
+    let subscriptions = (0..num_topics)
+        .map(|topic| filtered_subscription(&subscriber, topic))
+        .collect::<Vec<_>>();
+
+    block_on(async move {
+        for i in 0..NUM_MESSAGES {
+            publisher.send(TopicPayload::new(i % num_topics, i)).await.unwrap();
+        }
+        drop(publisher);
+        for subscription in subscriptions {
+            assert_eq!(subscription.count().await, NUM_MESSAGES / num_topics);
+        }
+    });
+}
+
+fn topic_indexed(num_topics: usize) {
+    let (mut publisher, subscriber_factory) = pubsub_channel(NUM_MESSAGES);
+    let subscriptions = (0..num_topics)
+        .map(|topic| subscriber_factory.get_subscription(topic))
+        .collect::<Vec<_>>();
+
+    block_on(async move {
+        for i in 0..NUM_MESSAGES {
+            publisher.send(TopicPayload::new(i % num_topics, i)).await.unwrap();
+        }
+        drop(publisher);
+        for subscription in subscriptions {
+            assert_eq!(subscription.count().await, NUM_MESSAGES / num_topics);
+        }
+    });
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Elements(NUM_MESSAGES as u64));
+    for num_topics in &[1, 10, 50] {
+        group.bench_with_input(BenchmarkId::new("broadcast_filter", num_topics), num_topics, |b, &n| {
+            b.iter(|| broadcast_filter(n))
+        });
+        group.bench_with_input(BenchmarkId::new("topic_indexed", num_topics), num_topics, |b, &n| {
+            b.iter(|| topic_indexed(n))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,99 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+/// The behaviour of a subscription's buffer when a message is published to it while it is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the oldest buffered message to make room for the new one
+    #[default]
+    DropOldest,
+    /// Drop the new message and keep the buffered messages
+    DropNewest,
+    /// Make the publisher wait until the subscription has room in its buffer. While a blocking subscription is full
+    /// the publisher will not accept new messages of its topics, so a slow blocking subscription applies backpressure
+    /// to them. Sending through the publisher's `Sink` interface waits while any blocking subscription is full.
+    Block,
+    /// Reject the new message, the publish fails and the message is not delivered to any subscription
+    Fail,
+}
+
+/// How a queue group chooses the member that receives each message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GroupSelection {
+    /// Deliver to each member in turn
+    #[default]
+    RoundRobin,
+    /// Deliver to the member with the fewest buffered messages, taking turns between members that are equally loaded
+    LeastLoaded,
+}
+
+/// Configuration of a pub-sub channel created by `pubsub_channel_with_config`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PubSubConfig {
+    /// The number of messages each subscription buffers unless it sets its own capacity, as well as the number of
+    /// recent messages kept to backfill subscriptions created after they were published. A buffer size of 0 keeps no
+    /// recent messages, while subscriptions still buffer at least one message.
+    pub buffer_size: usize,
+    /// The id used to label the channel, which makes debugging simpler
+    pub receiver_id: usize,
+    /// The overflow policy of subscriptions that do not set their own
+    pub overflow_policy: OverflowPolicy,
+    /// Whether the channel counts the messages published, delivered and dropped for each topic. The counters of every
+    /// topic that was published to are kept until they are reset, so channels whose topics are not a fixed set, e.g.
+    /// topics that include an id, should disable them or reset them regularly.
+    pub topic_stats: bool,
+}
+
+impl PubSubConfig {
+    pub fn new(buffer_size: usize) -> Self {
+        Self {
+            buffer_size,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_receiver_id(mut self, receiver_id: usize) -> Self {
+        self.receiver_id = receiver_id;
+        self
+    }
+
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    pub fn with_topic_stats(mut self, topic_stats: bool) -> Self {
+        self.topic_stats = topic_stats;
+        self
+    }
+}
+
+impl Default for PubSubConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 100,
+            receiver_id: 1,
+            overflow_policy: OverflowPolicy::default(),
+            topic_stats: true,
+        }
+    }
+}
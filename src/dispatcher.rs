@@ -0,0 +1,1581 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The dispatcher is the shared core of a pub-sub channel. Subscriptions are indexed by topic so that publishing a
+//! message only touches the queues of the subscriptions that are interested in it. Subscriptions that cannot be
+//! indexed by a single topic (e.g. wildcard patterns) are kept in a separate list and are consulted for every message.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    hash::Hash,
+    mem,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    time::SystemTime,
+};
+
+use futures::{channel::mpsc, ready, Stream};
+
+use crate::{
+    ChannelStats,
+    DeliveryReport,
+    GroupSelection,
+    Lagged,
+    LifecycleEvent,
+    OverflowPolicy,
+    PubSubConfig,
+    PubSubError,
+    PublisherId,
+    SubscriptionStats,
+    TopicPayload,
+    TopicStats,
+};
+
+/// Identifies a subscription for as long as it is registered with its channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SubscriptionId(u64);
+
+impl fmt::Display for SubscriptionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An item yielded by a subscription's receiver, either the next message or notice that messages were missed
+pub(crate) type ReceivedItem<T, M> = Result<Envelope<T, M>, Lagged>;
+/// A published message as it is held by the dispatcher. The message is stored once and shared by every subscription
+/// that it is routed to.
+pub(crate) type Envelope<T, M> = Arc<TopicPayload<T, Arc<M>>>;
+
+/// The span that was current when a message was published, or in which it was delivered. The publisher's span is kept
+/// with each queued copy of the message rather than in the message itself, so that messages kept in the history to
+/// backfill new subscriptions do not hold the publisher's span open.
+#[cfg(feature = "tracing")]
+pub(crate) type MessageSpan = tracing::Span;
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct MessageSpan;
+
+#[cfg(feature = "tracing")]
+fn current_span() -> MessageSpan {
+    tracing::Span::current()
+}
+
+#[cfg(not(feature = "tracing"))]
+fn current_span() -> MessageSpan {
+    MessageSpan
+}
+
+#[cfg(feature = "tracing")]
+fn no_span() -> MessageSpan {
+    tracing::Span::none()
+}
+
+#[cfg(not(feature = "tracing"))]
+fn no_span() -> MessageSpan {
+    MessageSpan
+}
+
+/// An additional condition on a published payload, i.e. its topic, content and metadata, that must hold for it to be
+/// queued
+pub(crate) type Predicate<T, M> = Box<dyn Fn(&TopicPayload<T, Arc<M>>) -> bool + Send>;
+
+/// Determines which published messages are routed to a subscription
+pub(crate) enum Filter<T> {
+    /// Matches any of a set of topics exactly, these subscriptions are indexed by each of their topics
+    Topics(HashSet<T>),
+    /// Matches any topic for which the predicate returns true
+    Matcher(Box<dyn Fn(&T) -> bool + Send>),
+}
+
+impl<T: Hash + Eq> Filter<T> {
+    pub fn topic(topic: T) -> Self {
+        Filter::Topics(Some(topic).into_iter().collect())
+    }
+
+    fn matches(&self, topic: &T) -> bool {
+        match self {
+            Filter::Topics(topics) => topics.contains(topic),
+            Filter::Matcher(f) => f(topic),
+        }
+    }
+}
+
+/// Membership of a queue group, whose members share the messages routed to them so that each message is queued for
+/// only one member
+pub(crate) struct QueueGroup {
+    name: String,
+    selection: GroupSelection,
+}
+
+/// Describes a subscription to be registered with the dispatcher
+pub(crate) struct SubscriptionSpec<T, M> {
+    filter: Filter<T>,
+    predicate: Option<Predicate<T, M>>,
+    overflow_policy: Option<OverflowPolicy>,
+    capacity: Option<usize>,
+    group: Option<QueueGroup>,
+    is_internal: bool,
+    is_backfilled: bool,
+    is_responder: bool,
+}
+
+impl<T, M> SubscriptionSpec<T, M> {
+    pub fn new(filter: Filter<T>) -> Self {
+        Self {
+            filter,
+            predicate: None,
+            overflow_policy: None,
+            capacity: None,
+            group: None,
+            is_internal: false,
+            is_backfilled: true,
+            is_responder: false,
+        }
+    }
+
+    /// Mark the subscription as the one a `Responder` receives requests on, so that publishing a request can count the
+    /// responders that received it
+    pub fn responder(mut self) -> Self {
+        self.is_responder = true;
+        self
+    }
+
+    /// Only deliver messages published after the subscription was created, rather than backfilling it with the
+    /// retained and recent messages of its topics
+    pub fn without_backfill(mut self) -> Self {
+        self.is_backfilled = false;
+        self
+    }
+
+    /// Mark the subscription as used internally by the crate, e.g. to receive the replies to a single request. Internal
+    /// subscriptions are short-lived and are left out of the channel's stats, subscription count and lifecycle events.
+    pub fn internal(mut self) -> Self {
+        self.is_internal = true;
+        self
+    }
+
+    /// Make the subscription a member of the named queue group. The group chooses members using the selection of its
+    /// oldest member.
+    pub fn with_group(mut self, name: String, selection: GroupSelection) -> Self {
+        self.group = Some(QueueGroup { name, selection });
+        self
+    }
+
+    /// Override the channel's buffer size for this subscription. The capacity is at least 1.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    pub fn with_predicate(mut self, predicate: Predicate<T, M>) -> Self {
+        self.predicate = Some(predicate);
+        self
+    }
+
+    /// Override the channel's overflow policy for this subscription
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = Some(overflow_policy);
+        self
+    }
+}
+
+impl<T, M> From<Filter<T>> for SubscriptionSpec<T, M> {
+    fn from(filter: Filter<T>) -> Self {
+        Self::new(filter)
+    }
+}
+
+/// The outcome of pushing a message to a queue
+enum Pushed<T, M> {
+    Enqueued,
+    /// The message was queued after evicting the oldest queued message
+    EnqueuedWithEviction(Envelope<T, M>),
+    /// The message was dropped because the queue is full
+    Dropped,
+}
+
+struct Queue<T, M> {
+    filter: Filter<T>,
+    predicate: Option<Predicate<T, M>>,
+    /// The queued messages, each tagged with the number of messages that were dropped immediately before it and
+    /// with the span it was published in
+    items: VecDeque<(u64, Envelope<T, M>, MessageSpan)>,
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+    /// The number of messages dropped after the last queued message
+    missed: u64,
+    /// The number of dropped messages that have not yet been reported by a `LifecycleEvent::Lagged`
+    unreported: u64,
+    /// True from the time a `LifecycleEvent::Lagged` is emitted for the queue until the subscription reaches a gap,
+    /// which limits the events to one for each gap
+    is_lag_reported: bool,
+    waker: Option<Waker>,
+    /// False once the subscription has been unsubscribed, after which it only yields the messages already queued
+    is_active: bool,
+    delivered: u64,
+    dropped: u64,
+    /// The largest number of messages that have been queued at once
+    max_depth: usize,
+    group: Option<QueueGroup>,
+    is_internal: bool,
+    is_responder: bool,
+}
+
+impl<T, M> Queue<T, M> {
+    /// Returns true if the message satisfies the subscription's predicate, this is checked before the message is
+    /// queued so that rejected messages are never delivered
+    fn admits(&self, item: &TopicPayload<T, Arc<M>>) -> bool {
+        self.predicate.as_ref().is_none_or(|predicate| predicate(item))
+    }
+
+    fn is_full(&self) -> bool {
+        self.items.len() >= self.capacity
+    }
+
+    /// Returns true if the queue holds back publishers, i.e. it is a full, live queue with the `Block` overflow policy
+    fn is_blocking(&self) -> bool {
+        self.is_active && self.overflow_policy == OverflowPolicy::Block && self.is_full()
+    }
+
+    fn push(&mut self, item: Envelope<T, M>, span: MessageSpan) -> Pushed<T, M> {
+        let mut pushed = Pushed::Enqueued;
+        if self.is_full() {
+            match self.overflow_policy {
+                OverflowPolicy::DropOldest => {
+                    if let Some((missed, evicted, _)) = self.items.pop_front() {
+                        match self.items.front_mut() {
+                            Some((next_missed, ..)) => *next_missed += missed + 1,
+                            None => self.missed += missed + 1,
+                        }
+                        self.dropped += 1;
+                        self.unreported += 1;
+                        pushed = Pushed::EnqueuedWithEviction(evicted);
+                    }
+                },
+                OverflowPolicy::DropNewest => {
+                    self.missed += 1;
+                    self.dropped += 1;
+                    self.unreported += 1;
+                    return Pushed::Dropped;
+                },
+                // Publishing waits for or fails on a full blocking or failing queue, so these can only exceed their
+                // capacity when they are backfilled or when several publishers stop waiting at the same time
+                OverflowPolicy::Block | OverflowPolicy::Fail => {},
+            }
+        }
+        self.items.push_back((mem::take(&mut self.missed), item, span));
+        self.max_depth = self.max_depth.max(self.items.len());
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        pushed
+    }
+
+    /// Returns the number of messages to report in a `LifecycleEvent::Lagged`, if the queue has dropped messages
+    /// since it last reached a gap and they have not been reported yet
+    fn take_lag_event(&mut self) -> Option<u64> {
+        if self.is_lag_reported || self.unreported == 0 {
+            return None;
+        }
+        self.is_lag_reported = true;
+        Some(mem::take(&mut self.unreported))
+    }
+
+    /// Take the next item from the queue, together with the span the message was published in. A gap left by dropped
+    /// messages is reported before the message that followed it.
+    fn pop(&mut self) -> Option<(ReceivedItem<T, M>, MessageSpan)> {
+        let missed = match self.items.front_mut() {
+            Some((missed, ..)) if *missed > 0 => mem::take(missed),
+            Some(_) => {
+                return self.items.pop_front().map(|(_, item, span)| {
+                    self.delivered += 1;
+                    (Ok(item), span)
+                })
+            },
+            None if self.missed > 0 => mem::take(&mut self.missed),
+            None => return None,
+        };
+        self.is_lag_reported = false;
+        Some((Err(Lagged { missed }), no_span()))
+    }
+}
+
+/// A published message tagged with the order in which it was published
+type Sequenced<T, M> = (u64, Envelope<T, M>);
+
+struct State<T, M> {
+    /// The most recently published messages, used to backfill new subscriptions
+    history: VecDeque<Sequenced<T, M>>,
+    /// The latest retained message of each topic
+    retained: HashMap<T, Sequenced<T, M>>,
+    next_seq: u64,
+    topics: HashMap<T, Vec<SubscriptionId>>,
+    matchers: Vec<SubscriptionId>,
+    queues: HashMap<SubscriptionId, Queue<T, M>>,
+    next_id: SubscriptionId,
+    /// The subscriptions whose queues are currently blocking, see `Queue::is_blocking`
+    blocking: HashSet<SubscriptionId>,
+    /// Publishers waiting for a full blocking queue to make room
+    publisher_wakers: Vec<Waker>,
+    /// Tasks waiting for every queue to be drained after the channel was closed
+    drain_wakers: Vec<Waker>,
+    /// The number of messages delivered by each queue group on each topic, used to take turns between the members
+    /// competing for that topic
+    group_turns: HashMap<(T, String), usize>,
+    /// Counters of every topic that has been published to
+    topic_stats: HashMap<T, TopicStats>,
+    /// Listeners for subscription lifecycle events
+    event_listeners: Vec<mpsc::UnboundedSender<LifecycleEvent<T>>>,
+    /// The number of live publisher handles, the channel is closed when the last one is dropped
+    num_publishers: usize,
+    next_publisher_id: u64,
+    is_closed: bool,
+}
+
+impl<T: Hash + Eq + Clone, M> State<T, M> {
+    /// Send a lifecycle event to every listener, forgetting the listeners that have gone away
+    fn emit(&mut self, event: LifecycleEvent<T>) {
+        self.event_listeners
+            .retain(|listener| listener.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Returns true if a full subscription with the `Block` overflow policy would receive a message of the given topic,
+    /// or if any such subscription is full when the topic is not known
+    fn is_blocked(&self, topic: Option<&T>) -> bool {
+        match topic {
+            Some(topic) => self
+                .blocking
+                .iter()
+                .any(|id| self.queues.get(id).is_some_and(|queue| queue.filter.matches(topic))),
+            None => !self.blocking.is_empty(),
+        }
+    }
+
+    /// Returns true once every subscription has yielded all of its queued messages, including any notice of missed
+    /// messages
+    fn is_drained(&self) -> bool {
+        self.queues
+            .values()
+            .all(|queue| queue.items.is_empty() && queue.missed == 0)
+    }
+
+    fn wake_drain_waiters(&mut self) {
+        for waker in self.drain_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn wake_publishers(&mut self) {
+        for waker in self.publisher_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Re-check whether a subscription holds back publishers after its queue or topics changed. Publishers waiting for
+    /// it are woken whenever it may no longer hold them back, including when it still has a full queue but has stopped
+    /// receiving some of the topics they are waiting to publish to, i.e. when `is_narrowed` is true.
+    fn update_blocking(&mut self, id: SubscriptionId, is_narrowed: bool) {
+        if self.queues.get(&id).is_some_and(Queue::is_blocking) {
+            if !self.blocking.insert(id) && is_narrowed {
+                self.wake_publishers();
+            }
+        } else if self.blocking.remove(&id) {
+            self.wake_publishers();
+        }
+    }
+
+    fn unindex_topic(&mut self, topic: &T, id: SubscriptionId) {
+        if let Some(ids) = self.topics.get_mut(topic) {
+            ids.retain(|i| *i != id);
+            if ids.is_empty() {
+                self.topics.remove(topic);
+            }
+        }
+    }
+}
+
+pub(crate) struct Dispatcher<T, M> {
+    config: PubSubConfig,
+    state: Mutex<State<T, M>>,
+}
+
+impl<T, M> Dispatcher<T, M>
+where T: Hash + Eq + Clone
+{
+    pub fn new(config: PubSubConfig) -> Self {
+        Self {
+            state: Mutex::new(State {
+                history: VecDeque::with_capacity(config.buffer_size),
+                retained: HashMap::new(),
+                next_seq: 0,
+                topics: HashMap::new(),
+                matchers: Vec::new(),
+                queues: HashMap::new(),
+                next_id: SubscriptionId(0),
+                blocking: HashSet::new(),
+                publisher_wakers: Vec::new(),
+                drain_wakers: Vec::new(),
+                group_turns: HashMap::new(),
+                topic_stats: HashMap::new(),
+                event_listeners: Vec::new(),
+                num_publishers: 0,
+                next_publisher_id: 0,
+                is_closed: false,
+            }),
+            config,
+        }
+    }
+
+    pub fn receiver_id(&self) -> usize {
+        self.config.receiver_id
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T, M>> {
+        // A panic while holding the lock cannot leave the state half-updated in a way that matters to other users
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Returns ready once no subscription with the `Block` overflow policy has a full queue, or with an error if the
+    /// channel is closed. This is used when the topic of the next message is not known yet.
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), PubSubError>> {
+        self.poll_ready_with(None, cx)
+    }
+
+    /// Returns ready once no full subscription with the `Block` overflow policy would receive a message of the topic,
+    /// or with an error if the channel is closed
+    pub fn poll_ready_for(&self, topic: &T, cx: &mut Context<'_>) -> Poll<Result<(), PubSubError>> {
+        self.poll_ready_with(Some(topic), cx)
+    }
+
+    fn poll_ready_with(&self, topic: Option<&T>, cx: &mut Context<'_>) -> Poll<Result<(), PubSubError>> {
+        let mut state = self.lock();
+        if state.is_closed {
+            return Poll::Ready(Err(PubSubError::ChannelClosed));
+        }
+        if state.is_blocked(topic) {
+            state.publisher_wakers.push(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Publish a message if that can be done without waiting for a blocking subscription of its topic
+    pub fn try_publish(&self, payload: TopicPayload<T, M>) -> Result<DeliveryReport, PubSubError> {
+        if self.lock().is_blocked(Some(payload.topic())) {
+            return Err(PubSubError::BufferFull);
+        }
+        self.publish(payload)
+    }
+
+    /// Route a message to the queue of every subscription interested in its topic and report how it was delivered. The
+    /// message is not published at all if it is routed to a full subscription with the `Fail` overflow policy.
+    pub fn publish(&self, payload: TopicPayload<T, M>) -> Result<DeliveryReport, PubSubError> {
+        self.publish_counting_responders(payload).map(|(report, _)| report)
+    }
+
+    /// Publish a message as with `publish`, and also return the number of `Responder` subscriptions that buffered it
+    pub fn publish_counting_responders(
+        &self,
+        payload: TopicPayload<T, M>,
+    ) -> Result<(DeliveryReport, usize), PubSubError> {
+        let span = current_span();
+        let mut payload = payload.map_message(Arc::new);
+        let mut state = self.lock();
+        if state.is_closed {
+            return Err(PubSubError::ChannelClosed);
+        }
+        let State {
+            history,
+            retained,
+            next_seq,
+            topics,
+            matchers,
+            queues,
+            blocking,
+            group_turns,
+            topic_stats,
+            ..
+        } = &mut *state;
+        payload.metadata.sequence = *next_seq;
+        payload.metadata.timestamp = SystemTime::now();
+        let payload = Arc::new(payload);
+
+        let indexed = topics.get(payload.topic()).map(Vec::as_slice).unwrap_or_default();
+        let mut targets = indexed
+            .iter()
+            .chain(matchers.iter().filter(|id| queues[*id].filter.matches(payload.topic())))
+            .copied()
+            .collect::<Vec<_>>();
+        let mut report = DeliveryReport {
+            matched: targets.len(),
+            ..Default::default()
+        };
+        targets.retain(|id| queues[id].admits(&payload));
+        let mut lagged = Vec::new();
+        let turns = select_group_members(payload.topic(), &mut targets, queues, group_turns);
+        let is_rejected = targets.iter().any(|id| is_rejecting(&queues[id]));
+        if is_rejected {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                receiver_id = self.config.receiver_id,
+                "Message rejected by a full subscription with the Fail overflow policy"
+            );
+            return Err(PubSubError::RejectedByPolicy);
+        }
+        group_turns.extend(turns);
+        let mut responders = 0;
+        for id in targets {
+            if let Some(queue) = queues.get_mut(&id) {
+                let pushed = queue.push(payload.clone(), span.clone());
+                if queue.is_responder && !matches!(pushed, Pushed::Dropped) {
+                    responders += 1;
+                }
+                match pushed {
+                    Pushed::Enqueued => report.enqueued += 1,
+                    Pushed::EnqueuedWithEviction(evicted) => {
+                        report.enqueued += 1;
+                        report.evicted += 1;
+                        if self.config.topic_stats {
+                            topic_stats.entry(evicted.topic().clone()).or_default().dropped += 1;
+                        }
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(
+                            receiver_id = self.config.receiver_id,
+                            subscription_id = %id,
+                            "Subscription buffer full, oldest message dropped"
+                        );
+                    },
+                    Pushed::Dropped => {
+                        report.dropped += 1;
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(
+                            receiver_id = self.config.receiver_id,
+                            subscription_id = %id,
+                            "Subscription buffer full, message dropped"
+                        );
+                    },
+                }
+                if let Some(missed) = queue.take_lag_event().filter(|_| !queue.is_internal) {
+                    lagged.push(LifecycleEvent::Lagged { id, missed });
+                }
+                if queue.is_blocking() {
+                    blocking.insert(id);
+                }
+            }
+        }
+        if self.config.topic_stats {
+            let stats = topic_stats.entry(payload.topic().clone()).or_default();
+            stats.published += 1;
+            stats.dropped += report.dropped as u64;
+        }
+
+        let seq = *next_seq;
+        *next_seq += 1;
+        if payload.is_retained() {
+            retained.insert(payload.topic().clone(), (seq, payload.clone()));
+        }
+        if self.config.buffer_size > 0 {
+            if history.len() == self.config.buffer_size {
+                history.pop_front();
+            }
+            history.push_back((seq, payload));
+        }
+        for event in lagged {
+            state.emit(event);
+        }
+        Ok((report, responders))
+    }
+
+    /// Returns true if any subscription's topics match the given topic. Content predicates are not considered, since
+    /// they can only be evaluated once the message exists.
+    pub fn has_subscribers(&self, topic: &T) -> bool {
+        let state = self.lock();
+        state.topics.contains_key(topic) ||
+            state
+                .matchers
+                .iter()
+                .any(|id| state.queues.get(id).is_some_and(|queue| queue.filter.matches(topic)))
+    }
+
+    /// Remove the retained message of a topic, so that new subscriptions no longer receive it. The message is also
+    /// removed from the history if it is still held there, while the topic's other recent messages are kept.
+    pub fn clear_retained(&self, topic: &T) {
+        let mut state = self.lock();
+        if let Some((seq, _)) = state.retained.remove(topic) {
+            state.history.retain(|(s, _)| *s != seq);
+        }
+    }
+
+    /// Register a new subscription. The subscription first receives the retained messages of matching topics that
+    /// have since left the channel history, followed by the matching messages that are still held in the history, so
+    /// that a subscription sees the same messages regardless of when it was created. If there are more of these than
+    /// fit in the subscription's buffer, only the most recent are queued.
+    pub fn subscribe<S: Into<SubscriptionSpec<T, M>>>(self: &Arc<Self>, spec: S) -> Receiver<T, M> {
+        let SubscriptionSpec {
+            filter,
+            predicate,
+            overflow_policy,
+            capacity,
+            group,
+            is_internal,
+            is_backfilled,
+            is_responder,
+        } = spec.into();
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id = SubscriptionId(id.0 + 1);
+
+        let history_start = state.history.front().map(|(seq, _)| *seq).unwrap_or(state.next_seq);
+        let mut retained = state
+            .retained
+            .values()
+            .filter(|(seq, item)| *seq < history_start && filter.matches(item.topic()))
+            .cloned()
+            .collect::<Vec<_>>();
+        retained.sort_by_key(|(seq, _)| *seq);
+
+        let mut queue = Queue {
+            filter,
+            predicate,
+            items: VecDeque::new(),
+            capacity: capacity.unwrap_or(self.config.buffer_size).max(1),
+            overflow_policy: overflow_policy.unwrap_or(self.config.overflow_policy),
+            missed: 0,
+            unreported: 0,
+            is_lag_reported: false,
+            waker: None,
+            is_active: true,
+            delivered: 0,
+            dropped: 0,
+            max_depth: 0,
+            group,
+            is_internal,
+            is_responder,
+        };
+        let backfill = retained
+            .into_iter()
+            .chain(
+                state
+                    .history
+                    .iter()
+                    .filter(|(_, item)| queue.filter.matches(item.topic()))
+                    .cloned(),
+            )
+            .filter(|(_, item)| queue.admits(item))
+            .collect::<Vec<_>>();
+        // Members of a queue group are not backfilled, as the messages may already have been delivered to the group
+        let skip = match queue.group {
+            Some(_) => backfill.len(),
+            None if !is_backfilled => backfill.len(),
+            None => backfill.len().saturating_sub(queue.capacity),
+        };
+        // Backfilled messages are not delivered in the span they were published in, which may have closed long ago
+        for (_, item) in backfill.into_iter().skip(skip) {
+            queue.push(item, no_span());
+        }
+
+        match &queue.filter {
+            Filter::Topics(topics) => {
+                for topic in topics {
+                    state.topics.entry(topic.clone()).or_default().push(id);
+                    if !queue.is_internal {
+                        state.emit(LifecycleEvent::Subscribed {
+                            id,
+                            topic: Some(topic.clone()),
+                        });
+                    }
+                }
+            },
+            Filter::Matcher(_) => {
+                state.matchers.push(id);
+                if !queue.is_internal {
+                    state.emit(LifecycleEvent::Subscribed { id, topic: None });
+                }
+            },
+        }
+        if queue.is_blocking() {
+            state.blocking.insert(id);
+        }
+        state.queues.insert(id, queue);
+
+        Receiver {
+            id,
+            dispatcher: self.clone(),
+            span: no_span(),
+        }
+    }
+
+    /// Stop routing messages to a subscription. Messages that are already queued for it can still be received, after
+    /// which the subscription ends. Returns false if the subscription was already unsubscribed.
+    fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut state = self.lock();
+        let queue = match state.queues.get_mut(&id) {
+            Some(queue) if queue.is_active => queue,
+            _ => return false,
+        };
+        queue.is_active = false;
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        let is_internal = queue.is_internal;
+        let topics = match &queue.filter {
+            Filter::Topics(topics) => topics.iter().cloned().map(Some).collect(),
+            Filter::Matcher(_) => vec![None],
+        };
+        for topic in topics {
+            match &topic {
+                Some(topic) => state.unindex_topic(topic, id),
+                None => state.matchers.retain(|i| *i != id),
+            }
+            if !is_internal {
+                state.emit(LifecycleEvent::Unsubscribed { id, topic });
+            }
+        }
+        if state.blocking.remove(&id) {
+            state.wake_publishers();
+        }
+        true
+    }
+
+    /// Remove a subscription and everything queued for it, called when its receiver is dropped
+    fn remove_subscription(&self, id: SubscriptionId) {
+        self.unsubscribe(id);
+        let mut state = self.lock();
+        if let Some(QueueGroup { name, .. }) = state.queues.remove(&id).and_then(|queue| queue.group) {
+            let is_empty = !state
+                .queues
+                .values()
+                .any(|queue| queue.group.as_ref().is_some_and(|group| group.name == name));
+            if is_empty {
+                state.group_turns.retain(|(_, group), _| *group != name);
+            }
+        }
+        state.wake_drain_waiters();
+    }
+
+    /// Returns ready once every subscription has drained its queue or been dropped. Only meaningful once the channel
+    /// is closed, as publishing would otherwise refill the queues.
+    pub fn poll_drained(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.lock();
+        if state.is_drained() {
+            Poll::Ready(())
+        } else {
+            state.drain_wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Returns the number of live subscriptions, i.e. those that have neither been dropped nor unsubscribed, leaving
+    /// out the crate's internal subscriptions
+    pub fn num_subscriptions(&self) -> usize {
+        self.lock()
+            .queues
+            .values()
+            .filter(|queue| queue.is_active && !queue.is_internal)
+            .count()
+    }
+
+    /// Register a publisher handle and return its id
+    pub fn add_publisher(&self) -> PublisherId {
+        let mut state = self.lock();
+        state.num_publishers += 1;
+        state.next_publisher_id += 1;
+        PublisherId(state.next_publisher_id)
+    }
+
+    /// Release a publisher handle, closing the channel if it was the last one
+    pub fn remove_publisher(&self) {
+        let is_last = {
+            let mut state = self.lock();
+            state.num_publishers -= 1;
+            state.num_publishers == 0
+        };
+        if is_last {
+            self.close();
+        }
+    }
+
+    /// Close the channel. Subscriptions will yield the messages that are already queued and then end.
+    pub fn close(&self) {
+        let mut state = self.lock();
+        if state.is_closed {
+            return;
+        }
+        state.is_closed = true;
+        for queue in state.queues.values_mut() {
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+        // Publishers waiting for a blocking subscription fail with `ChannelClosed` once they are woken
+        state.wake_publishers();
+        state.emit(LifecycleEvent::Closed);
+        state.event_listeners.clear();
+    }
+
+    /// Take a snapshot of the channel's topic and subscription counters
+    pub fn stats(&self) -> ChannelStats<T> {
+        let state = self.lock();
+        let mut subscriptions = state
+            .queues
+            .iter()
+            .filter(|(_, queue)| !queue.is_internal)
+            .map(|(id, queue)| SubscriptionStats {
+                id: *id,
+                topics: match &queue.filter {
+                    Filter::Topics(topics) if queue.is_active => topics.iter().cloned().collect(),
+                    _ => Vec::new(),
+                },
+                delivered: queue.delivered,
+                dropped: queue.dropped,
+                queue_depth: queue.items.len(),
+                max_queue_depth: queue.max_depth,
+            })
+            .collect::<Vec<_>>();
+        subscriptions.sort_by_key(|stats| stats.id);
+        ChannelStats {
+            receiver_id: self.config.receiver_id,
+            topics: state.topic_stats.clone(),
+            subscriptions,
+        }
+    }
+
+    /// Reset the counters of the channel. The counters of every topic are removed, and those of each subscription
+    /// start again from zero, with its largest queue depth set to the number of messages currently buffered.
+    pub fn reset_stats(&self) {
+        let mut state = self.lock();
+        state.topic_stats.clear();
+        for queue in state.queues.values_mut() {
+            queue.delivered = 0;
+            queue.dropped = 0;
+            queue.max_depth = queue.items.len();
+        }
+    }
+
+    /// Returns a stream of the lifecycle events of the channel's subscriptions, starting with a `Subscribed` event for
+    /// each live subscription. The stream ends after the channel is closed.
+    pub fn lifecycle_events(&self) -> mpsc::UnboundedReceiver<LifecycleEvent<T>> {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut state = self.lock();
+        if state.is_closed {
+            let _ = sender.unbounded_send(LifecycleEvent::Closed);
+            return receiver;
+        }
+        let mut live = state
+            .queues
+            .iter()
+            .filter(|(_, queue)| queue.is_active && !queue.is_internal)
+            .flat_map(|(id, queue)| -> Vec<_> {
+                match &queue.filter {
+                    Filter::Topics(topics) => topics.iter().map(|topic| (*id, Some(topic.clone()))).collect(),
+                    Filter::Matcher(_) => vec![(*id, None)],
+                }
+            })
+            .collect::<Vec<_>>();
+        live.sort_by_key(|(id, _)| *id);
+        for (id, topic) in live {
+            let _ = sender.unbounded_send(LifecycleEvent::Subscribed { id, topic });
+        }
+        state.event_listeners.push(sender);
+        receiver
+    }
+
+    /// Add a topic to a topic-indexed subscription. Returns false if the subscription already had the topic or does
+    /// not match by topic. The latest retained message of the topic, if any, is queued for the subscription.
+    fn add_topic(&self, id: SubscriptionId, topic: T) -> bool {
+        let mut state = self.lock();
+        let State {
+            retained,
+            topics,
+            queues,
+            topic_stats,
+            ..
+        } = &mut *state;
+        let queue = match queues.get_mut(&id) {
+            Some(queue) if queue.is_active => queue,
+            _ => return false,
+        };
+        match &mut queue.filter {
+            Filter::Topics(subscribed) if !subscribed.contains(&topic) => {
+                subscribed.insert(topic.clone());
+            },
+            _ => return false,
+        }
+        if let Some((_, item)) = retained.get(&topic) {
+            if queue.admits(item) {
+                if let Pushed::EnqueuedWithEviction(evicted) = queue.push(item.clone(), no_span()) {
+                    if self.config.topic_stats {
+                        topic_stats.entry(evicted.topic().clone()).or_default().dropped += 1;
+                    }
+                }
+            }
+        }
+        let lagged = queue.take_lag_event();
+        topics.entry(topic.clone()).or_default().push(id);
+        state.update_blocking(id, false);
+        if let Some(missed) = lagged {
+            state.emit(LifecycleEvent::Lagged { id, missed });
+        }
+        state.emit(LifecycleEvent::Subscribed { id, topic: Some(topic) });
+        true
+    }
+
+    /// Remove a topic from a topic-indexed subscription. Messages of the topic that are already queued are kept.
+    /// Returns false if the subscription did not have the topic.
+    fn remove_topic(&self, id: SubscriptionId, topic: &T) -> bool {
+        let mut state = self.lock();
+        let removed = match state.queues.get_mut(&id).filter(|q| q.is_active).map(|q| &mut q.filter) {
+            Some(Filter::Topics(subscribed)) => subscribed.remove(topic),
+            _ => false,
+        };
+        if removed {
+            state.unindex_topic(topic, id);
+            state.update_blocking(id, true);
+            state.emit(LifecycleEvent::Unsubscribed {
+                id,
+                topic: Some(topic.clone()),
+            });
+        }
+        removed
+    }
+
+    fn topics(&self, id: SubscriptionId) -> Vec<T> {
+        match self.lock().queues.get(&id).filter(|q| q.is_active).map(|q| &q.filter) {
+            Some(Filter::Topics(topics)) => topics.iter().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Poll for the next message of a subscription, together with the span it is delivered in. If messages were
+    /// dropped from the queue, the number of missed messages is reported before the messages that followed them.
+    fn poll_recv(&self, id: SubscriptionId, cx: &mut Context<'_>) -> Poll<Option<(ReceivedItem<T, M>, MessageSpan)>> {
+        let mut state = self.lock();
+        let is_closed = state.is_closed;
+        let queue = match state.queues.get_mut(&id) {
+            Some(queue) => queue,
+            None => return Poll::Ready(None),
+        };
+        match queue.pop() {
+            Some((item, span)) => {
+                if !queue.is_blocking() && state.blocking.remove(&id) {
+                    state.wake_publishers();
+                }
+                if let (Ok(item), true) = (&item, self.config.topic_stats) {
+                    state.topic_stats.entry(item.topic().clone()).or_default().delivered += 1;
+                }
+                #[cfg(feature = "tracing")]
+                let span = self.delivery_span(id, &item, &span);
+                state.wake_drain_waiters();
+                Poll::Ready(Some((item, span)))
+            },
+            None if is_closed || !queue.is_active => Poll::Ready(None),
+            None => {
+                queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+impl<T, M> Dispatcher<T, M>
+where T: Hash + Eq + Clone
+{
+    /// Create the span a message is delivered to a subscription in, as a child of the span it was published in
+    #[cfg(feature = "tracing")]
+    fn delivery_span(&self, id: SubscriptionId, item: &ReceivedItem<T, M>, publish_span: &MessageSpan) -> MessageSpan {
+        match item {
+            Ok(_) => {
+                let span = tracing::debug_span!(
+                    parent: publish_span,
+                    "pubsub_deliver",
+                    receiver_id = self.config.receiver_id,
+                    subscription_id = %id
+                );
+                span.in_scope(|| tracing::trace!("Message delivered to subscription"));
+                span
+            },
+            Err(Lagged { missed }) => {
+                tracing::debug!(
+                    receiver_id = self.config.receiver_id,
+                    subscription_id = %id,
+                    missed,
+                    "Subscription lagged behind the publisher"
+                );
+                no_span()
+            },
+        }
+    }
+}
+
+/// Narrow the targets of a message down to a single member of each queue group among them, leaving the targets that
+/// are not in a group untouched
+fn select_group_members<T: Hash + Eq + Clone, M>(
+    topic: &T,
+    targets: &mut Vec<SubscriptionId>,
+    queues: &HashMap<SubscriptionId, Queue<T, M>>,
+    group_turns: &HashMap<(T, String), usize>,
+) -> Vec<((T, String), usize)> {
+    let mut groups = HashMap::<&str, Vec<SubscriptionId>>::new();
+    targets.retain(|id| match &queues[id].group {
+        Some(group) => {
+            groups.entry(group.name.as_str()).or_default().push(*id);
+            false
+        },
+        None => true,
+    });
+    let mut turns = Vec::with_capacity(groups.len());
+    for (name, mut members) in groups {
+        members.sort_unstable();
+        let selection = queues[&members[0]].group.as_ref().map(|g| g.selection);
+        // A full member with the `Fail` overflow policy only rejects the message if no other member can take it
+        if members.iter().any(|id| !is_rejecting(&queues[id])) {
+            members.retain(|id| !is_rejecting(&queues[id]));
+        }
+        if selection == Some(GroupSelection::LeastLoaded) {
+            let least = members
+                .iter()
+                .map(|id| queues[id].items.len())
+                .min()
+                .unwrap_or_default();
+            members.retain(|id| queues[id].items.len() == least);
+        }
+        let key = (topic.clone(), name.to_string());
+        let turn = group_turns.get(&key).copied().unwrap_or_default();
+        targets.push(members[turn % members.len()]);
+        turns.push((key, turn.wrapping_add(1)));
+    }
+    turns
+}
+
+/// Returns true if the queue is full and rejects further messages due to the `Fail` overflow policy
+fn is_rejecting<T, M>(queue: &Queue<T, M>) -> bool {
+    queue.overflow_policy == OverflowPolicy::Fail && queue.is_full()
+}
+
+/// The receiving end of a single subscription, deregistered from the dispatcher when dropped
+pub(crate) struct Receiver<T, M>
+where T: Hash + Eq + Clone
+{
+    id: SubscriptionId,
+    dispatcher: Arc<Dispatcher<T, M>>,
+    /// The span the most recently received message was delivered in
+    span: MessageSpan,
+}
+
+impl<T, M> Receiver<T, M>
+where T: Hash + Eq + Clone
+{
+    pub fn id(&self) -> SubscriptionId {
+        self.id
+    }
+
+    pub fn unsubscribe(&self) -> bool {
+        self.dispatcher.unsubscribe(self.id)
+    }
+
+    pub fn add_topic(&self, topic: T) -> bool {
+        self.dispatcher.add_topic(self.id, topic)
+    }
+
+    pub fn remove_topic(&self, topic: &T) -> bool {
+        self.dispatcher.remove_topic(self.id, topic)
+    }
+
+    pub fn topics(&self) -> Vec<T> {
+        self.dispatcher.topics(self.id)
+    }
+
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+}
+
+impl<T, M> Stream for Receiver<T, M>
+where T: Hash + Eq + Clone
+{
+    type Item = ReceivedItem<T, M>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match ready!(self.dispatcher.poll_recv(self.id, cx)) {
+            Some((item, span)) => {
+                self.span = span;
+                Poll::Ready(Some(item))
+            },
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl<T, M> Drop for Receiver<T, M>
+where T: Hash + Eq + Clone
+{
+    fn drop(&mut self) {
+        self.dispatcher.remove_subscription(self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{executor::block_on, StreamExt};
+
+    use super::*;
+
+    #[test]
+    fn routes_only_to_interested_subscriptions() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(10)));
+        let a = dispatcher.subscribe(Filter::topic("a"));
+        let b = dispatcher.subscribe(Filter::topic("b"));
+        let all = dispatcher.subscribe(Filter::Matcher(Box::new(|_| true)));
+
+        dispatcher.publish(TopicPayload::new("a", 1)).unwrap();
+        dispatcher.publish(TopicPayload::new("b", 2)).unwrap();
+        dispatcher.publish(TopicPayload::new("a", 3)).unwrap();
+
+        {
+            let state = dispatcher.lock();
+            assert_eq!(state.topics.len(), 2);
+            assert_eq!(state.queues[&a.id].items.len(), 2);
+            assert_eq!(state.queues[&b.id].items.len(), 1);
+            assert_eq!(state.queues[&all.id].items.len(), 3);
+        }
+        dispatcher.close();
+
+        let messages = |r: Receiver<&'static str, u32>| block_on(r.map(|p| **p.unwrap().message()).collect::<Vec<_>>());
+        assert_eq!(messages(a), vec![1, 3]);
+        assert_eq!(messages(b), vec![2]);
+        assert_eq!(messages(all), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn dropped_receivers_are_deregistered() {
+        let dispatcher = Arc::new(Dispatcher::<_, u32>::new(PubSubConfig::new(10)));
+        let a1 = dispatcher.subscribe(Filter::topic("a"));
+        let a2 = dispatcher.subscribe(Filter::topic("a"));
+        let all = dispatcher.subscribe(Filter::Matcher(Box::new(|_| true)));
+        let ab = dispatcher.subscribe(Filter::Topics(vec!["a", "b"].into_iter().collect()));
+
+        drop(a1);
+        assert_eq!(dispatcher.lock().topics["a"].len(), 2);
+        drop(a2);
+        drop(all);
+        assert_eq!(dispatcher.lock().topics.len(), 2);
+        drop(ab);
+
+        let state = dispatcher.lock();
+        assert!(state.topics.is_empty());
+        assert!(state.matchers.is_empty());
+        assert!(state.queues.is_empty());
+    }
+
+    #[test]
+    fn slow_subscriptions_drop_oldest_messages() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(2)));
+        let a = dispatcher.subscribe(Filter::topic("a"));
+        for i in 0..5 {
+            dispatcher.publish(TopicPayload::new("a", i)).unwrap();
+        }
+        dispatcher.close();
+        let items = block_on(a.map(|p| p.map(|p| **p.message())).collect::<Vec<_>>());
+        assert_eq!(items, vec![Err(Lagged { missed: 3 }), Ok(3), Ok(4)]);
+    }
+
+    #[test]
+    fn topics_can_be_changed_while_subscribed() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(10)));
+        dispatcher.publish(TopicPayload::new_retained("b", 1)).unwrap();
+        let receiver = dispatcher.subscribe(Filter::topic("a"));
+        assert!(receiver.add_topic("c"));
+        assert!(!receiver.add_topic("a"));
+        dispatcher.publish(TopicPayload::new("a", 2)).unwrap();
+        dispatcher.publish(TopicPayload::new("b", 3)).unwrap();
+        dispatcher.publish(TopicPayload::new_retained("c", 4)).unwrap();
+        assert!(receiver.remove_topic(&"a"));
+        assert!(!receiver.remove_topic(&"a"));
+        dispatcher.publish(TopicPayload::new("a", 5)).unwrap();
+        assert!(receiver.add_topic("b"));
+        assert_eq!(receiver.topics().len(), 2);
+        assert!(!dispatcher.lock().topics.contains_key("a"));
+
+        let matcher = dispatcher.subscribe(Filter::Matcher(Box::new(|_| true)));
+        assert!(!matcher.add_topic("a"));
+        assert!(matcher.topics().is_empty());
+        drop(matcher);
+
+        dispatcher.close();
+        let messages = block_on(receiver.map(|p| **p.unwrap().message()).collect::<Vec<_>>());
+        assert_eq!(messages, vec![2, 4, 1]);
+    }
+
+    #[test]
+    fn predicates_are_checked_before_queueing() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(10)));
+        dispatcher.publish(TopicPayload::new("a", 1)).unwrap();
+        dispatcher.publish(TopicPayload::new("a", 20)).unwrap();
+        let large = dispatcher
+            .subscribe(SubscriptionSpec::new(Filter::topic("a")).with_predicate(Box::new(|p| **p.message() >= 10)));
+        let b_or_odd = dispatcher.subscribe(
+            SubscriptionSpec::new(Filter::Matcher(Box::new(|_| true)))
+                .with_predicate(Box::new(|p| *p.topic() == "b" || **p.message() % 2 == 1)),
+        );
+        dispatcher.publish(TopicPayload::new("a", 3)).unwrap();
+        dispatcher.publish(TopicPayload::new("a", 30)).unwrap();
+        dispatcher.publish(TopicPayload::new("b", 4)).unwrap();
+
+        {
+            let state = dispatcher.lock();
+            assert_eq!(state.queues[&large.id].items.len(), 2);
+            assert_eq!(state.queues[&b_or_odd.id].items.len(), 3);
+        }
+        dispatcher.close();
+
+        let messages = |r: Receiver<&'static str, u32>| block_on(r.map(|p| **p.unwrap().message()).collect::<Vec<_>>());
+        assert_eq!(messages(large), vec![20, 30]);
+        assert_eq!(messages(b_or_odd), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn drop_newest_reports_the_gap_after_buffered_messages() {
+        let dispatcher = Arc::new(Dispatcher::new(
+            PubSubConfig::new(2).with_overflow_policy(OverflowPolicy::DropNewest),
+        ));
+        let a = dispatcher.subscribe(Filter::topic("a"));
+        for i in 0..5 {
+            dispatcher.publish(TopicPayload::new("a", i)).unwrap();
+        }
+        let mut a = a.map(|p| p.map(|p| **p.message()));
+        assert_eq!(block_on(a.next()), Some(Ok(0)));
+        dispatcher.publish(TopicPayload::new("a", 5)).unwrap();
+        dispatcher.close();
+        let items = block_on(a.collect::<Vec<_>>());
+        assert_eq!(items, vec![Ok(1), Err(Lagged { missed: 3 }), Ok(5)]);
+    }
+
+    #[test]
+    fn publishing_reports_delivery() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(1)));
+        assert_eq!(
+            dispatcher.publish(TopicPayload::new("a", 1)).unwrap(),
+            DeliveryReport::default()
+        );
+        let _oldest = dispatcher.subscribe(Filter::topic("a"));
+        let _newest = dispatcher
+            .subscribe(SubscriptionSpec::new(Filter::topic("a")).with_overflow_policy(OverflowPolicy::DropNewest));
+        let _even = dispatcher
+            .subscribe(SubscriptionSpec::new(Filter::topic("a")).with_predicate(Box::new(|p| **p.message() % 2 == 0)));
+        let _all = dispatcher.subscribe(Filter::Matcher(Box::new(|_| true)));
+        let _other = dispatcher.subscribe(Filter::topic("b"));
+
+        // The first message was backfilled into the subscriptions to "a" without a predicate, filling their buffers
+        assert_eq!(dispatcher.publish(TopicPayload::new("a", 3)).unwrap(), DeliveryReport {
+            matched: 4,
+            enqueued: 2,
+            evicted: 2,
+            dropped: 1,
+        });
+        assert_eq!(dispatcher.publish(TopicPayload::new("b", 4)).unwrap(), DeliveryReport {
+            matched: 2,
+            enqueued: 2,
+            evicted: 1,
+            dropped: 0,
+        });
+    }
+
+    #[test]
+    fn buffers_at_least_one_message() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(0)));
+        let a = dispatcher.subscribe(Filter::topic("a"));
+        let b = dispatcher.subscribe(SubscriptionSpec::new(Filter::topic("b")).with_capacity(0));
+        for i in 0..2 {
+            dispatcher.publish(TopicPayload::new("a", i)).unwrap();
+            dispatcher.publish(TopicPayload::new("b", i)).unwrap();
+        }
+        dispatcher.close();
+
+        let items = |r: Receiver<&'static str, u32>| block_on(r.map(|p| p.map(|p| **p.message())).collect::<Vec<_>>());
+        assert_eq!(items(a), vec![Err(Lagged { missed: 1 }), Ok(1)]);
+        assert_eq!(items(b), vec![Err(Lagged { missed: 1 }), Ok(1)]);
+    }
+
+    #[test]
+    fn tracks_interest_in_topics() {
+        let dispatcher = Arc::new(Dispatcher::<_, u32>::new(PubSubConfig::new(1)));
+        assert!(!dispatcher.has_subscribers(&"a"));
+        let a = dispatcher.subscribe(Filter::topic("a"));
+        let b = dispatcher.subscribe(Filter::Matcher(Box::new(|t| *t == "b")));
+        assert!(dispatcher.has_subscribers(&"a"));
+        assert!(dispatcher.has_subscribers(&"b"));
+        assert!(!dispatcher.has_subscribers(&"c"));
+        drop(a);
+        drop(b);
+        assert!(!dispatcher.has_subscribers(&"a"));
+        assert!(!dispatcher.has_subscribers(&"b"));
+    }
+
+    #[test]
+    fn fail_policy_rejects_the_whole_publish() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(1)));
+        let lossy = dispatcher.subscribe(Filter::topic("a"));
+        let strict =
+            dispatcher.subscribe(SubscriptionSpec::new(Filter::topic("a")).with_overflow_policy(OverflowPolicy::Fail));
+        dispatcher.publish(TopicPayload::new("a", 1)).unwrap();
+        assert_eq!(
+            dispatcher.publish(TopicPayload::new("a", 2)),
+            Err(PubSubError::RejectedByPolicy)
+        );
+        // Messages that are not routed to the full subscription are unaffected
+        dispatcher.publish(TopicPayload::new("b", 3)).unwrap();
+        dispatcher.close();
+
+        let messages = |r: Receiver<&'static str, u32>| block_on(r.map(|p| **p.unwrap().message()).collect::<Vec<_>>());
+        assert_eq!(messages(lossy), vec![1]);
+        assert_eq!(messages(strict), vec![1]);
+    }
+
+    #[test]
+    fn block_policy_only_holds_back_its_topics() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(1)));
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let _blocking =
+            dispatcher.subscribe(SubscriptionSpec::new(Filter::topic("a")).with_overflow_policy(OverflowPolicy::Block));
+        let _pattern = dispatcher.subscribe(
+            SubscriptionSpec::new(Filter::Matcher(Box::new(|t: &&str| t.starts_with('c'))))
+                .with_overflow_policy(OverflowPolicy::Block),
+        );
+        dispatcher.publish(TopicPayload::new("a", 1)).unwrap();
+        dispatcher.publish(TopicPayload::new("c", 1)).unwrap();
+        assert_eq!(dispatcher.lock().blocking.len(), 2);
+
+        assert_eq!(dispatcher.poll_ready_for(&"a", &mut cx), Poll::Pending);
+        assert_eq!(dispatcher.poll_ready_for(&"c1", &mut cx), Poll::Pending);
+        assert_eq!(dispatcher.poll_ready_for(&"b", &mut cx), Poll::Ready(Ok(())));
+        assert!(dispatcher.try_publish(TopicPayload::new("b", 2)).is_ok());
+        assert_eq!(
+            dispatcher.try_publish(TopicPayload::new("a", 2)),
+            Err(PubSubError::BufferFull)
+        );
+        // Without a topic any full blocking subscription holds back the publisher
+        assert_eq!(dispatcher.poll_ready(&mut cx), Poll::Pending);
+    }
+
+    #[test]
+    fn block_policy_holds_back_publishers() {
+        let dispatcher = Arc::new(Dispatcher::new(
+            PubSubConfig::new(1).with_overflow_policy(OverflowPolicy::Block),
+        ));
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut a = dispatcher.subscribe(Filter::topic("a"));
+        assert_eq!(dispatcher.poll_ready(&mut cx), Poll::Ready(Ok(())));
+        dispatcher.publish(TopicPayload::new("a", 1)).unwrap();
+        assert_eq!(dispatcher.poll_ready(&mut cx), Poll::Pending);
+        assert_eq!(dispatcher.lock().publisher_wakers.len(), 1);
+        assert_eq!(
+            dispatcher.try_publish(TopicPayload::new("a", 2)),
+            Err(PubSubError::BufferFull)
+        );
+
+        assert!(block_on(a.next()).is_some());
+        assert!(dispatcher.lock().publisher_wakers.is_empty());
+        assert_eq!(dispatcher.poll_ready(&mut cx), Poll::Ready(Ok(())));
+        dispatcher.try_publish(TopicPayload::new("a", 2)).unwrap();
+        assert_eq!(dispatcher.poll_ready(&mut cx), Poll::Pending);
+        drop(a);
+        assert_eq!(dispatcher.poll_ready(&mut cx), Poll::Ready(Ok(())));
+        dispatcher.close();
+        assert_eq!(
+            dispatcher.poll_ready(&mut cx),
+            Poll::Ready(Err(PubSubError::ChannelClosed))
+        );
+        assert_eq!(
+            dispatcher.publish(TopicPayload::new("a", 3)),
+            Err(PubSubError::ChannelClosed)
+        );
+    }
+
+    #[test]
+    fn subscriptions_have_independent_capacities() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(4)));
+        for i in 0..3 {
+            dispatcher.publish(TopicPayload::new("a", i)).unwrap();
+        }
+        let small = dispatcher.subscribe(SubscriptionSpec::new(Filter::topic("a")).with_capacity(2));
+        let large = dispatcher.subscribe(SubscriptionSpec::new(Filter::topic("a")).with_capacity(10));
+        for i in 3..8 {
+            dispatcher.publish(TopicPayload::new("a", i)).unwrap();
+        }
+        dispatcher.close();
+
+        let items = |r: Receiver<&'static str, u32>| block_on(r.map(|p| p.map(|p| **p.message())).collect::<Vec<_>>());
+        // Backfilled messages that do not fit are skipped without being reported as missed
+        assert_eq!(items(small), vec![Err(Lagged { missed: 5 }), Ok(6), Ok(7)]);
+        assert_eq!(items(large), (0..8).map(Ok).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retained_messages_are_delivered_once() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(2)));
+        dispatcher.publish(TopicPayload::new_retained("a", 1)).unwrap();
+        dispatcher.publish(TopicPayload::new_retained("b", 2)).unwrap();
+        dispatcher.publish(TopicPayload::new("c", 3)).unwrap();
+        // "a" has left the history and is only retained, "b" is both retained and in the history
+        let a1 = dispatcher.subscribe(Filter::topic("a"));
+        let b1 = dispatcher.subscribe(Filter::topic("b"));
+        dispatcher.publish(TopicPayload::new_retained("a", 4)).unwrap();
+        let a2 = dispatcher.subscribe(Filter::topic("a"));
+        dispatcher.clear_retained(&"b");
+        let b2 = dispatcher.subscribe(Filter::topic("b"));
+        dispatcher.close();
+
+        let messages = |r: Receiver<&'static str, u32>| block_on(r.map(|p| **p.unwrap().message()).collect::<Vec<_>>());
+        assert_eq!(messages(a1), vec![1, 4]);
+        assert_eq!(messages(b1), vec![2]);
+        assert_eq!(messages(a2), vec![4]);
+        assert!(messages(b2).is_empty());
+    }
+
+    #[test]
+    fn unsubscribed_receivers_drain_and_end() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(4)));
+        let unsubscribed = dispatcher.subscribe(Filter::topic("a"));
+        let dropped = dispatcher.subscribe(Filter::Matcher(Box::new(|_| true)));
+        assert_ne!(unsubscribed.id(), dropped.id());
+        assert_eq!(dispatcher.num_subscriptions(), 2);
+
+        dispatcher.publish(TopicPayload::new("a", 1)).unwrap();
+        assert!(unsubscribed.unsubscribe());
+        assert!(!unsubscribed.unsubscribe());
+        assert!(!unsubscribed.add_topic("b"));
+        assert!(unsubscribed.topics().is_empty());
+        assert_eq!(dispatcher.num_subscriptions(), 1);
+
+        drop(dropped);
+        assert_eq!(dispatcher.num_subscriptions(), 0);
+        assert!(!dispatcher.has_subscribers(&"a"));
+        assert_eq!(dispatcher.publish(TopicPayload::new("a", 2)).unwrap().matched, 0);
+
+        // The channel is still open, but the unsubscribed receiver ends once its queue is drained
+        let messages = block_on(unsubscribed.map(|p| **p.unwrap().message()).collect::<Vec<_>>());
+        assert_eq!(messages, vec![1]);
+    }
+
+    #[test]
+    fn queue_groups_share_messages() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(10)));
+        let member = |group: &str, selection| {
+            dispatcher.subscribe(SubscriptionSpec::new(Filter::topic("a")).with_group(group.to_string(), selection))
+        };
+        let mut busy = member("least", GroupSelection::LeastLoaded);
+        let idle = member("least", GroupSelection::LeastLoaded);
+        let other = member("other", GroupSelection::RoundRobin);
+
+        dispatcher.publish(TopicPayload::new("a", 0)).unwrap();
+        dispatcher.publish(TopicPayload::new("a", 1)).unwrap();
+        dispatcher.publish(TopicPayload::new("a", 2)).unwrap();
+        // Equally loaded members take turns, otherwise the least loaded member is chosen
+        assert_eq!(**block_on(busy.next()).unwrap().unwrap().message(), 0);
+        let report = dispatcher.publish(TopicPayload::new("a", 3)).unwrap();
+        assert_eq!(report.matched, 3);
+        assert_eq!(report.enqueued, 2);
+
+        // A new member is not backfilled with messages the group has already been given
+        let late = member("least", GroupSelection::LeastLoaded);
+        dispatcher.close();
+
+        let messages = |r: Receiver<&'static str, u32>| block_on(r.map(|p| **p.unwrap().message()).collect::<Vec<_>>());
+        assert_eq!(messages(busy), vec![2]);
+        assert_eq!(messages(idle), vec![1, 3]);
+        assert_eq!(messages(other), vec![0, 1, 2, 3]);
+        assert!(messages(late).is_empty());
+    }
+
+    #[test]
+    fn queue_groups_skip_full_failing_members() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(10)));
+        let member = |group, spec: SubscriptionSpec<_, _>| {
+            dispatcher.subscribe(spec.with_group(group, GroupSelection::RoundRobin))
+        };
+        let first = member("rr".to_string(), SubscriptionSpec::new(Filter::topic("a")));
+        let second = member("rr".to_string(), SubscriptionSpec::new(Filter::topic("a")));
+        let mut strict = dispatcher.subscribe(
+            SubscriptionSpec::new(Filter::topic("a"))
+                .with_capacity(1)
+                .with_overflow_policy(OverflowPolicy::Fail),
+        );
+
+        // A rejected message does not use up the group's turn
+        dispatcher.publish(TopicPayload::new("a", 0)).unwrap();
+        assert_eq!(
+            dispatcher.publish(TopicPayload::new("a", 1)),
+            Err(PubSubError::RejectedByPolicy)
+        );
+        block_on(strict.next());
+        dispatcher.publish(TopicPayload::new("a", 2)).unwrap();
+
+        // A full member with the Fail overflow policy is passed over while another member has room
+        let full = member(
+            "workers".to_string(),
+            SubscriptionSpec::new(Filter::topic("b"))
+                .with_capacity(1)
+                .with_overflow_policy(OverflowPolicy::Fail),
+        );
+        let roomy = member("workers".to_string(), SubscriptionSpec::new(Filter::topic("b")));
+        for i in 10..14 {
+            dispatcher.publish(TopicPayload::new("b", i)).unwrap();
+        }
+        dispatcher.close();
+
+        let messages = |r: Receiver<&'static str, u32>| block_on(r.map(|p| **p.unwrap().message()).collect::<Vec<_>>());
+        assert_eq!(messages(first), vec![0]);
+        assert_eq!(messages(second), vec![2]);
+        assert_eq!(messages(full), vec![10]);
+        assert_eq!(messages(roomy), vec![11, 12, 13]);
+    }
+
+    #[test]
+    fn queue_groups_take_turns_per_topic() {
+        let dispatcher = Arc::new(Dispatcher::new(PubSubConfig::new(10)));
+        let member = |topic| {
+            dispatcher.subscribe(
+                SubscriptionSpec::new(Filter::topic(topic))
+                    .with_group("workers".to_string(), GroupSelection::RoundRobin),
+            )
+        };
+        let members = vec![member("a"), member("a"), member("b"), member("b")];
+
+        // Members sharing a group name on different topics do not share turns
+        for n in 0..4 {
+            dispatcher.publish(TopicPayload::new("a", n)).unwrap();
+            dispatcher.publish(TopicPayload::new("b", n)).unwrap();
+        }
+        dispatcher.close();
+
+        let messages = members
+            .into_iter()
+            .map(|r| block_on(r.map(|p| **p.unwrap().message()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec![vec![0, 2], vec![1, 3], vec![0, 2], vec![1, 3]]);
+    }
+}
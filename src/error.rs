@@ -0,0 +1,69 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use std::{error::Error, fmt};
+
+/// Errors that can occur when publishing to a pub-sub channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PubSubError {
+    /// The channel has been closed and no longer accepts messages
+    ChannelClosed,
+    /// No subscription is interested in the message's topic
+    NoSubscribers,
+    /// The message cannot be published without waiting for a full subscription with the `Block` overflow policy
+    BufferFull,
+    /// The message was rejected because it was routed to a full subscription with the `Fail` overflow policy
+    RejectedByPolicy,
+    /// The operation did not complete before its deadline
+    Timeout,
+}
+
+impl fmt::Display for PubSubError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PubSubError::ChannelClosed => write!(f, "The pub-sub channel is closed"),
+            PubSubError::NoSubscribers => write!(f, "No subscription is interested in the topic"),
+            PubSubError::BufferFull => write!(f, "A subscription buffer is full"),
+            PubSubError::RejectedByPolicy => {
+                write!(f, "Message rejected by the overflow policy of a full subscription")
+            },
+            PubSubError::Timeout => write!(f, "The operation timed out"),
+        }
+    }
+}
+
+impl Error for PubSubError {}
+
+/// Yielded by a lag-aware subscription when it fell behind and the oldest messages in its buffer were dropped to
+/// make room for new ones. `missed` is the number of messages that were lost since the previous item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged {
+    pub missed: u64,
+}
+
+impl fmt::Display for Lagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Subscription lagged and missed {} message(s)", self.missed)
+    }
+}
+
+impl Error for Lagged {}
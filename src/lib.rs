@@ -19,22 +19,89 @@
 // SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
 // USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
-use std::fmt::Debug;
+use std::{fmt::Debug, hash::Hash, sync::Arc};
 
-use futures::{future, prelude::*, stream::Fuse};
-use tari_broadcast_channel::{bounded, Publisher, Subscriber};
+use futures::{prelude::*, stream::Fuse};
+
+mod config;
+pub use config::{GroupSelection, OverflowPolicy, PubSubConfig};
+
+mod dispatcher;
+pub use dispatcher::SubscriptionId;
+use dispatcher::{Dispatcher, Filter, SubscriptionSpec};
+
+mod error;
+pub use error::{Lagged, PubSubError};
+
+mod lifecycle;
+pub use lifecycle::LifecycleEvent;
+
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "prometheus")]
+pub use prometheus::render_prometheus;
+
+mod metadata;
+pub use metadata::{Metadata, PublisherId};
+
+mod publisher;
+pub use publisher::{DeliveryReport, TopicPublisher};
+
+mod request;
+pub use request::{GatherLimits, Replies, Requester, Responder};
+
+mod stats;
+pub use stats::{ChannelStats, SubscriptionStats, TopicStats};
+
+mod subscription;
+pub use subscription::{DynamicSubscription, Subscription};
+
+mod topic;
+pub use topic::{
+    HierarchicalTopic,
+    TopicError,
+    TopicPattern,
+    MULTI_LEVEL_WILDCARD,
+    SINGLE_LEVEL_WILDCARD,
+    TOPIC_SEPARATOR,
+};
 
 /// The container for a message that is passed along the pub-sub channel that contains a Topic to define the type of
 /// message and the message itself.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TopicPayload<T, M> {
     topic: T,
     message: M,
+    retain: bool,
+    metadata: Metadata,
+    reply_to: Option<T>,
 }
 
 impl<T, M> TopicPayload<T, M> {
     pub fn new(topic: T, message: M) -> Self {
-        Self { topic, message }
+        Self {
+            topic,
+            message,
+            retain: false,
+            metadata: Metadata::default(),
+            reply_to: None,
+        }
+    }
+
+    /// Create a payload that the channel retains as the latest value of its topic. Subscriptions created after it was
+    /// published receive the retained value before any live messages.
+    pub fn new_retained(topic: T, message: M) -> Self {
+        Self {
+            topic,
+            message,
+            retain: true,
+            metadata: Metadata::default(),
+            reply_to: None,
+        }
+    }
+
+    pub fn is_retained(&self) -> bool {
+        self.retain
     }
 
     pub fn topic(&self) -> &T {
@@ -44,58 +111,313 @@ impl<T, M> TopicPayload<T, M> {
     pub fn message(&self) -> &M {
         &self.message
     }
+
+    /// Returns the payload's metadata. The sequence number, timestamp and publisher id are only set once the payload
+    /// has been published.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Set the correlation id of the payload, relating it to other messages
+    pub fn with_correlation_id<S: Into<String>>(mut self, correlation_id: S) -> Self {
+        self.metadata.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    /// Returns the topic that replies to this payload should be published to, if it is a request
+    pub fn reply_to(&self) -> Option<&T> {
+        self.reply_to.as_ref()
+    }
+
+    /// Set the topic that replies to this payload should be published to
+    pub fn with_reply_to(mut self, topic: T) -> Self {
+        self.reply_to = Some(topic);
+        self
+    }
+
+    /// Add a header to the payload, replacing any previous value of the header
+    pub fn with_header<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Self {
+        self.metadata.headers.insert(name.into(), value.into());
+        self
+    }
+
+    pub(crate) fn with_publisher_id(mut self, publisher_id: PublisherId) -> Self {
+        self.metadata.publisher_id = Some(publisher_id);
+        self
+    }
+
+    pub fn into_topic(self) -> T {
+        self.topic
+    }
+
+    pub fn into_message(self) -> M {
+        self.message
+    }
+
+    /// Consume the payload and return its topic and message
+    pub fn into_parts(self) -> (T, M) {
+        (self.topic, self.message)
+    }
+
+    pub(crate) fn map_message<N, F: FnOnce(M) -> N>(self, f: F) -> TopicPayload<T, N> {
+        TopicPayload {
+            topic: self.topic,
+            message: f(self.message),
+            retain: self.retain,
+            metadata: self.metadata,
+            reply_to: self.reply_to,
+        }
+    }
 }
 
-pub type TopicPublisher<T, M> = Publisher<TopicPayload<T, M>>;
-pub type TopicSubscriber<T, M> = Subscriber<TopicPayload<T, M>>;
+impl<T: Clone, M: Clone> TopicPayload<T, Arc<M>> {
+    /// Clone a payload held by the dispatcher into one that owns its message
+    pub(crate) fn to_owned_payload(&self) -> TopicPayload<T, M> {
+        TopicPayload {
+            topic: self.topic.clone(),
+            message: (*self.message).clone(),
+            retain: self.retain,
+            metadata: self.metadata.clone(),
+            reply_to: self.reply_to.clone(),
+        }
+    }
+}
 
-/// This structure hold the Subscriber end of a Pub-Sub channel and can be used to create new filtered subscriber
-/// channels.
+/// This structure holds the subscriber end of a Pub-Sub channel and can be used to create new subscriptions. Each
+/// subscription is registered with the channel's dispatcher, which only routes messages of the requested topic to it.
 pub struct TopicSubscriptionFactory<T, M> {
-    subscriber: TopicSubscriber<T, M>,
+    dispatcher: Arc<Dispatcher<T, M>>,
+}
+
+impl<T, M> TopicSubscriptionFactory<T, M>
+where
+    T: Hash + Eq + Clone + Send,
+    M: Send,
+{
+    pub(crate) fn new(dispatcher: Arc<Dispatcher<T, M>>) -> Self {
+        TopicSubscriptionFactory { dispatcher }
+    }
+
+    /// Returns the number of live subscriptions to the channel. Subscriptions stop counting as live when they are
+    /// dropped or unsubscribed.
+    pub fn num_subscriptions(&self) -> usize {
+        self.dispatcher.num_subscriptions()
+    }
+
+    /// Returns the receiver id used to label this channel
+    pub fn receiver_id(&self) -> usize {
+        self.dispatcher.receiver_id()
+    }
+
+    /// Returns a snapshot of the channel's counters: how many messages were published, delivered and dropped for each
+    /// topic, and how far behind each live subscription is
+    pub fn stats(&self) -> ChannelStats<T> {
+        self.dispatcher.stats()
+    }
+
+    /// Reset the channel's counters, forgetting every topic that has been published to. Channels that publish to an
+    /// open-ended set of topics can call this after collecting the stats to bound the memory used by the counters.
+    pub fn reset_stats(&self) {
+        self.dispatcher.reset_stats()
+    }
+
+    /// Returns a stream of events describing the lifecycle of the channel's subscriptions: when they subscribe to or
+    /// unsubscribe from a topic, when they lag behind and when the channel is closed. The stream starts with a
+    /// `Subscribed` event for each live subscription and ends once the channel is closed. Events are buffered without
+    /// limit until they are consumed.
+    pub fn get_lifecycle_events(&self) -> impl Stream<Item = LifecycleEvent<T>> {
+        self.dispatcher.lifecycle_events()
+    }
+
+    /// Provide a topic and this function will return a stream that yields the messages published to that topic as
+    /// `Arc<M>`. Each message is stored once and shared by every subscription, so unlike `get_subscription` the message
+    /// is never cloned and does not need to implement `Clone`.
+    pub fn get_arc_subscription(&self, topic: T) -> Subscription<T, M, Arc<M>> {
+        Subscription::with_map(self.dispatcher.subscribe(Filter::topic(topic)), |item| {
+            item.ok().map(|item| item.message().clone())
+        })
+    }
 }
 
 impl<T, M> TopicSubscriptionFactory<T, M>
 where
-    T: Eq + Send,
+    T: Hash + Eq + Clone + Send,
     M: Clone + Send,
 {
-    pub fn new(subscriber: TopicSubscriber<T, M>) -> Self {
-        TopicSubscriptionFactory { subscriber }
-    }
-
-    /// Provide a subscriber (which will be consumed) and a topic to filter it by and this function will return a stream
-    /// that yields only the desired messages
-    pub fn get_subscription(&self, topic: T) -> impl Stream<Item = M> {
-        self.subscriber.clone().filter_map(move |item| {
-            let result = if item.topic() == &topic {
-                Some(item.message.clone())
-            } else {
-                None
-            };
-            future::ready(result)
+    /// Provide a topic and this function will return a stream that yields only the messages published to that topic
+    pub fn get_subscription(&self, topic: T) -> Subscription<T, M> {
+        Subscription::new(self.dispatcher.subscribe(Filter::topic(topic)))
+    }
+
+    /// Provide a topic and this function will return a stream that yields the messages published to that topic, or a
+    /// `Lagged` error in place of any messages that were dropped because the subscription fell behind, so that the
+    /// consumer can detect the gap and resynchronise.
+    pub fn get_subscription_with_lag(&self, topic: T) -> Subscription<T, M, Result<M, Lagged>> {
+        Subscription::with_map(self.dispatcher.subscribe(Filter::topic(topic)), |item| {
+            Some(item.map(|item| (**item.message()).clone()))
+        })
+    }
+
+    /// Provide a topic and a buffer capacity and this function will return a stream that yields the messages published
+    /// to that topic. The subscription buffers up to `capacity` messages instead of the channel's buffer size, so that
+    /// buffering can be tuned for each consumer. A subscription always buffers at least one message, so a capacity of
+    /// 0 is treated as 1.
+    pub fn get_subscription_with_capacity(&self, topic: T, capacity: usize) -> Subscription<T, M> {
+        Subscription::new(
+            self.dispatcher
+                .subscribe(SubscriptionSpec::new(Filter::topic(topic)).with_capacity(capacity)),
+        )
+    }
+
+    /// Provide a topic and an overflow policy and this function will return a stream that yields the messages published
+    /// to that topic, using the given policy instead of the channel's policy when the subscription's buffer is full
+    pub fn get_subscription_with_policy(&self, topic: T, overflow_policy: OverflowPolicy) -> Subscription<T, M> {
+        Subscription::new(
+            self.dispatcher
+                .subscribe(SubscriptionSpec::new(Filter::topic(topic)).with_overflow_policy(overflow_policy)),
+        )
+    }
+
+    /// Provide a topic and the name of a queue group and this function will return a subscription that competes with
+    /// the group's other members for the messages published to that topic: each message is delivered to only one
+    /// member, chosen using `selection`. A group chooses members using the selection of its oldest member, and new
+    /// members are not backfilled with earlier messages, which may already have been handled by the group.
+    pub fn get_queue_subscription<S: Into<String>>(
+        &self,
+        topic: T,
+        group: S,
+        selection: GroupSelection,
+    ) -> Subscription<T, M> {
+        Subscription::new(
+            self.dispatcher
+                .subscribe(SubscriptionSpec::new(Filter::topic(topic)).with_group(group.into(), selection)),
+        )
+    }
+
+    /// Provide a topic and this function will return a stream that yields the payloads published to that topic, i.e.
+    /// each message together with its topic
+    pub fn get_subscription_with_topic(&self, topic: T) -> Subscription<T, M, TopicPayload<T, M>> {
+        Subscription::with_map(self.dispatcher.subscribe(Filter::topic(topic)), |item| {
+            item.ok().map(|item| item.to_owned_payload())
+        })
+    }
+
+    /// Provide a topic and this function will return a stream that yields the full envelope of each message published
+    /// to that topic, i.e. the payload together with its metadata, or a `Lagged` error in place of any messages that
+    /// were dropped because the subscription fell behind
+    pub fn get_envelope_subscription(&self, topic: T) -> Subscription<T, M, Result<TopicPayload<T, M>, Lagged>> {
+        Subscription::with_map(self.dispatcher.subscribe(Filter::topic(topic)), |item| {
+            Some(item.map(|item| item.to_owned_payload()))
         })
     }
 
+    /// Provide a set of topics and this function will return a single stream that yields the messages published to any
+    /// of them, in the order they were published
+    pub fn get_subscription_multi<I>(&self, topics: I) -> Subscription<T, M>
+    where I: IntoIterator<Item = T> {
+        Subscription::new(self.dispatcher.subscribe(Filter::Topics(topics.into_iter().collect())))
+    }
+
+    /// Provide a set of topics and this function will return a single stream that yields the payloads published to any
+    /// of them, in the order they were published
+    pub fn get_subscription_multi_with_topic<I>(&self, topics: I) -> Subscription<T, M, TopicPayload<T, M>>
+    where I: IntoIterator<Item = T> {
+        Subscription::with_map(
+            self.dispatcher.subscribe(Filter::Topics(topics.into_iter().collect())),
+            |item| item.ok().map(|item| item.to_owned_payload()),
+        )
+    }
+
+    /// Provide a topic and a predicate on the message content and this function will return a stream that yields only
+    /// the messages published to that topic for which the predicate returns true. The predicate is evaluated when the
+    /// message is published, so rejected messages are never buffered for or cloned into the subscription.
+    pub fn get_filtered_subscription<F>(&self, topic: T, predicate: F) -> Subscription<T, M>
+    where F: Fn(&M) -> bool + Send + 'static {
+        Subscription::new(
+            self.dispatcher
+                .subscribe(SubscriptionSpec::new(Filter::topic(topic)).with_predicate(Box::new(
+                    move |payload: &TopicPayload<T, Arc<M>>| predicate(payload.message()),
+                ))),
+        )
+    }
+
+    /// Provide a predicate on the topic and content of a message and this function will return a stream that yields
+    /// every message for which the predicate returns true, regardless of topic. As with `get_filtered_subscription`,
+    /// the predicate is evaluated when the message is published.
+    pub fn get_subscription_where<F>(&self, predicate: F) -> Subscription<T, M>
+    where F: Fn(&T, &M) -> bool + Send + 'static {
+        Subscription::new(self.dispatcher.subscribe(
+            SubscriptionSpec::new(Filter::Matcher(Box::new(|_| true))).with_predicate(Box::new(
+                move |payload: &TopicPayload<T, Arc<M>>| predicate(payload.topic(), payload.message()),
+            )),
+        ))
+    }
+
+    /// Provide an initial set of topics and this function will return a subscription whose topics can be changed
+    /// while it is live, using `DynamicSubscription::add_topic` and `DynamicSubscription::remove_topic`
+    pub fn get_dynamic_subscription<I>(&self, topics: I) -> DynamicSubscription<T, M>
+    where I: IntoIterator<Item = T> {
+        DynamicSubscription::new(self.dispatcher.subscribe(Filter::Topics(topics.into_iter().collect())))
+    }
+
     /// Provide a fused version of the subscription stream so that domain modules don't need to know about fuse()
-    pub fn get_subscription_fused(&self, topic: T) -> Fuse<impl Stream<Item = M>> {
+    pub fn get_subscription_fused(&self, topic: T) -> Fuse<Subscription<T, M>> {
         self.get_subscription(topic).fuse()
     }
 }
 
+impl<T, M> TopicSubscriptionFactory<T, M>
+where
+    T: AsRef<str> + Hash + Eq + Clone + Send,
+    M: Clone + Send,
+{
+    /// Provide a topic pattern, which may include `+` and `#` wildcards, and this function will return a stream that
+    /// yields the messages of every topic matched by the pattern
+    pub fn get_pattern_subscription(&self, pattern: TopicPattern) -> Subscription<T, M> {
+        Subscription::new(
+            self.dispatcher
+                .subscribe(Filter::Matcher(Box::new(move |topic: &T| pattern.matches(topic)))),
+        )
+    }
+
+    /// Provide a topic pattern and this function will return a stream that yields the payloads of every topic matched
+    /// by the pattern, so that the subscriber knows which topic each message was published to
+    pub fn get_pattern_subscription_with_topic(&self, pattern: TopicPattern) -> Subscription<T, M, TopicPayload<T, M>> {
+        Subscription::with_map(
+            self.dispatcher
+                .subscribe(Filter::Matcher(Box::new(move |topic: &T| pattern.matches(topic)))),
+            |item| item.ok().map(|item| item.to_owned_payload()),
+        )
+    }
+}
+
+/// Create a Topic based Pub-Sub channel using the provided configuration, which returns the Publisher side of the
+/// channel and TopicSubscriptionFactory which can produce multiple subscribers for provided topics.
+pub fn pubsub_channel_with_config<T: Hash + Eq + Clone + Send, M: Send>(
+    config: PubSubConfig,
+) -> (TopicPublisher<T, M>, TopicSubscriptionFactory<T, M>) {
+    let dispatcher = Arc::new(Dispatcher::new(config));
+    (
+        TopicPublisher::new(dispatcher.clone()),
+        TopicSubscriptionFactory::new(dispatcher),
+    )
+}
+
 /// Create Topic based Pub-Sub channel which returns the Publisher side of the channel and TopicSubscriptionFactory
 /// which can produce multiple subscribers for provided topics. The initial receiver id is required and used to label
 /// the subscribers, which makes debugging simpler.
-pub fn pubsub_channel_with_id<T: Send + Eq, M: Send + Clone>(
+///
+/// `size` is the number of messages each subscription buffers before its oldest unread messages are dropped, as well
+/// as the number of recent messages kept to backfill subscriptions created after they were published.
+pub fn pubsub_channel_with_id<T: Hash + Eq + Clone + Send, M: Send>(
     size: usize,
     receiver_id: usize,
 ) -> (TopicPublisher<T, M>, TopicSubscriptionFactory<T, M>) {
-    let (publisher, subscriber): (TopicPublisher<T, M>, TopicSubscriber<T, M>) = bounded(size, receiver_id);
-    (publisher, TopicSubscriptionFactory::new(subscriber))
+    pubsub_channel_with_config(PubSubConfig::new(size).with_receiver_id(receiver_id))
 }
 
 /// Create a topi-based pub-sub channel with a default receiver id of 1
-pub fn pubsub_channel<T: Send + Eq, M: Send + Clone>(
+pub fn pubsub_channel<T: Hash + Eq + Clone + Send, M: Send>(
     size: usize,
 ) -> (TopicPublisher<T, M>, TopicSubscriptionFactory<T, M>) {
     pubsub_channel_with_id(size, 1)
@@ -103,6 +425,11 @@ pub fn pubsub_channel<T: Send + Eq, M: Send + Clone>(
 
 #[cfg(test)]
 mod test {
+    use std::{
+        thread,
+        time::{Duration, Instant, SystemTime},
+    };
+
     use futures::executor::block_on;
 
     use super::*;
@@ -114,6 +441,7 @@ mod test {
         #[derive(Debug, Clone)]
         struct Dummy {
             a: u32,
+            #[allow(dead_code)]
             b: String,
         }
 
@@ -209,4 +537,1033 @@ mod test {
         assert_eq!(topic2[2].a, 6);
         assert_eq!(topic2[3].a, 22);
     }
+
+    #[test]
+    fn pattern_subscription() {
+        let (mut publisher, subscriber_factory) = pubsub_channel(10);
+
+        let topic = |s: &str| HierarchicalTopic::new(s).unwrap();
+        let messages = vec![
+            TopicPayload::new(topic("wallet/1/balance"), 1),
+            TopicPayload::new(topic("wallet/1/tx"), 2),
+            TopicPayload::new(topic("wallet/2/balance"), 3),
+            TopicPayload::new(topic("base_node/tip"), 4),
+            TopicPayload::new(topic("wallet/2/balance/pending"), 5),
+        ];
+
+        block_on(async {
+            for m in messages {
+                publisher.send(m).await.unwrap();
+            }
+        });
+
+        let balances = subscriber_factory.get_pattern_subscription(TopicPattern::new("wallet/+/balance").unwrap());
+        let wallet = subscriber_factory.get_pattern_subscription(TopicPattern::new("wallet/#").unwrap());
+        drop(publisher);
+
+        let balances = block_on(balances.collect::<Vec<u32>>());
+        assert_eq!(balances, vec![1, 3]);
+        let wallet = block_on(wallet.collect::<Vec<u32>>());
+        assert_eq!(wallet, vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn subscription_with_topic() {
+        let (mut publisher, subscriber_factory) = pubsub_channel(10);
+        let topic = |s: &str| HierarchicalTopic::new(s).unwrap();
+
+        let balances =
+            subscriber_factory.get_pattern_subscription_with_topic(TopicPattern::new("wallet/+/balance").unwrap());
+        let wallet1 = subscriber_factory.get_subscription_with_topic(topic("wallet/1/balance"));
+
+        block_on(async {
+            publisher
+                .send(TopicPayload::new(topic("wallet/1/balance"), 10))
+                .await
+                .unwrap();
+            publisher
+                .send(TopicPayload::new(topic("wallet/1/tx"), 11))
+                .await
+                .unwrap();
+            publisher
+                .send(TopicPayload::new(topic("wallet/2/balance"), 20))
+                .await
+                .unwrap();
+        });
+        drop(publisher);
+
+        let balances = block_on(balances.map(TopicPayload::into_parts).collect::<Vec<_>>());
+        assert_eq!(balances, vec![
+            (topic("wallet/1/balance"), 10),
+            (topic("wallet/2/balance"), 20)
+        ]);
+        let wallet1 = block_on(wallet1.collect::<Vec<_>>());
+        assert_eq!(wallet1.len(), 1);
+        assert_eq!(wallet1[0].topic(), &topic("wallet/1/balance"));
+        assert_eq!(wallet1[0].clone().into_topic(), topic("wallet/1/balance"));
+        assert_eq!(wallet1[0].clone().into_message(), 10);
+    }
+
+    #[test]
+    fn multi_topic_subscription() {
+        let (mut publisher, subscriber_factory) = pubsub_channel(10);
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        enum Topic {
+            NewBlock,
+            Reorg,
+            Mempool,
+            Shutdown,
+        }
+
+        let sync = subscriber_factory.get_subscription_multi(vec![Topic::NewBlock, Topic::Reorg, Topic::Shutdown]);
+        let sync_with_topic = subscriber_factory.get_subscription_multi_with_topic(vec![Topic::Reorg, Topic::Shutdown]);
+
+        block_on(async {
+            for (i, topic) in [
+                Topic::NewBlock,
+                Topic::Mempool,
+                Topic::Reorg,
+                Topic::NewBlock,
+                Topic::Mempool,
+                Topic::Shutdown,
+            ]
+            .iter()
+            .enumerate()
+            {
+                publisher.send(TopicPayload::new(*topic, i)).await.unwrap();
+            }
+        });
+        drop(publisher);
+
+        assert_eq!(block_on(sync.collect::<Vec<_>>()), vec![0, 2, 3, 5]);
+        let items = block_on(sync_with_topic.map(TopicPayload::into_parts).collect::<Vec<_>>());
+        assert_eq!(items, vec![(Topic::Reorg, 2), (Topic::Shutdown, 5)]);
+    }
+
+    #[test]
+    fn dynamic_subscription() {
+        let (mut publisher, subscriber_factory) = pubsub_channel(10);
+        let mut subscription = subscriber_factory.get_dynamic_subscription(vec!["Topic1"]);
+
+        block_on(async {
+            publisher.send(TopicPayload::new("Topic1", 1)).await.unwrap();
+            publisher.send(TopicPayload::new("Topic2", 2)).await.unwrap();
+        });
+        assert!(subscription.add_topic("Topic2"));
+        block_on(async {
+            publisher.send(TopicPayload::new("Topic2", 3)).await.unwrap();
+        });
+        // Buffered messages of a topic are still delivered after unsubscribing from it
+        assert!(subscription.remove_topic(&"Topic2"));
+        assert_eq!(subscription.topics(), vec!["Topic1"]);
+        block_on(async {
+            publisher.send(TopicPayload::new("Topic2", 4)).await.unwrap();
+            publisher.send(TopicPayload::new("Topic1", 5)).await.unwrap();
+        });
+        assert_eq!(block_on(subscription.next()), Some(1));
+        drop(publisher);
+
+        assert_eq!(block_on(subscription.collect::<Vec<_>>()), vec![3, 5]);
+    }
+
+    #[test]
+    fn filtered_subscriptions() {
+        let (mut publisher, subscriber_factory) = pubsub_channel(10);
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct TransactionEvent {
+            wallet_id: u32,
+            amount: u64,
+        }
+
+        let large =
+            subscriber_factory.get_filtered_subscription("Transaction", |tx: &TransactionEvent| tx.amount > 100);
+        let wallet2 = subscriber_factory
+            .get_subscription_where(|topic, tx: &TransactionEvent| *topic != "Other" && tx.wallet_id == 2);
+
+        block_on(async {
+            for (topic, wallet_id, amount) in &[
+                ("Transaction", 1, 50),
+                ("Transaction", 1, 500),
+                ("Transaction", 2, 20),
+                ("Other", 2, 1000),
+                ("Transaction", 2, 200),
+            ] {
+                publisher
+                    .send(TopicPayload::new(*topic, TransactionEvent {
+                        wallet_id: *wallet_id,
+                        amount: *amount,
+                    }))
+                    .await
+                    .unwrap();
+            }
+        });
+        drop(publisher);
+
+        let large = block_on(large.map(|tx| tx.amount).collect::<Vec<_>>());
+        assert_eq!(large, vec![500, 200]);
+        let wallet2 = block_on(wallet2.map(|tx| tx.amount).collect::<Vec<_>>());
+        assert_eq!(wallet2, vec![20, 200]);
+    }
+
+    #[test]
+    fn arc_subscription() {
+        // The message type does not implement Clone
+        #[derive(Debug, PartialEq)]
+        struct Block(Vec<u8>);
+
+        let (mut publisher, subscriber_factory) = pubsub_channel(10);
+        let sub1 = subscriber_factory.get_arc_subscription("Block");
+        let sub2 = subscriber_factory.get_arc_subscription("Block");
+
+        block_on(async {
+            publisher
+                .send(TopicPayload::new("Block", Block(vec![1; 1024])))
+                .await
+                .unwrap();
+            publisher
+                .send(TopicPayload::new("Other", Block(vec![2])))
+                .await
+                .unwrap();
+        });
+        drop(publisher);
+
+        let blocks1 = block_on(sub1.collect::<Vec<_>>());
+        let blocks2 = block_on(sub2.collect::<Vec<_>>());
+        assert_eq!(blocks1.len(), 1);
+        assert_eq!(blocks2.len(), 1);
+        assert_eq!(*blocks1[0], Block(vec![1; 1024]));
+        // Both subscriptions share the same message
+        assert!(Arc::ptr_eq(&blocks1[0], &blocks2[0]));
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let (mut publisher, subscriber_factory) = pubsub_channel_with_config(PubSubConfig::new(2));
+        let critical = subscriber_factory.get_subscription_with_policy("Topic1", OverflowPolicy::Block);
+        let telemetry = subscriber_factory.get_subscription("Topic1");
+
+        // The publisher waits for the critical subscription, while the telemetry subscription drops old messages
+        let producer = thread::spawn(move || {
+            block_on(async {
+                for i in 0..20 {
+                    publisher.send(TopicPayload::new("Topic1", i)).await.unwrap();
+                }
+            })
+        });
+        let received = block_on(critical.collect::<Vec<u32>>());
+        producer.join().unwrap();
+        assert_eq!(received, (0..20).collect::<Vec<_>>());
+        assert_eq!(block_on(telemetry.collect::<Vec<u32>>()), vec![18, 19]);
+
+        let (mut publisher, subscriber_factory) =
+            pubsub_channel_with_config(PubSubConfig::new(1).with_overflow_policy(OverflowPolicy::Fail));
+        let _subscription = subscriber_factory.get_subscription("Topic1");
+        block_on(async {
+            publisher.send(TopicPayload::new("Topic1", 1)).await.unwrap();
+            assert_eq!(
+                publisher.send(TopicPayload::new("Topic1", 2)).await,
+                Err(PubSubError::RejectedByPolicy)
+            );
+        });
+    }
+
+    #[test]
+    fn publisher_errors() {
+        let (publisher, subscriber_factory) = pubsub_channel(1);
+        let other_publisher = publisher.clone();
+        let critical = subscriber_factory.get_subscription_with_policy("Critical", OverflowPolicy::Block);
+        let _strict = subscriber_factory.get_subscription_with_policy("Strict", OverflowPolicy::Fail);
+
+        block_on(async {
+            publisher.publish(TopicPayload::new("Strict", 1)).await.unwrap();
+            publisher.publish(TopicPayload::new("Critical", 2)).await.unwrap();
+        });
+        assert_eq!(
+            publisher.try_publish(TopicPayload::new("Critical", 3)),
+            Err(PubSubError::BufferFull)
+        );
+        // Messages of other topics are not held back by the full blocking subscription
+        publisher.try_publish(TopicPayload::new("Other", 3)).unwrap();
+        drop(critical);
+        match publisher.try_publish(TopicPayload::new("Strict", 4)) {
+            Err(PubSubError::RejectedByPolicy) => {},
+            res => panic!("Unexpected result {:?}", res),
+        }
+        publisher.try_publish(TopicPayload::new("Other", 5)).unwrap();
+
+        other_publisher.close();
+        assert_eq!(
+            block_on(publisher.publish(TopicPayload::new("Other", 6))),
+            Err(PubSubError::ChannelClosed)
+        );
+    }
+
+    #[test]
+    fn close_releases_blocked_publishers() {
+        let (publisher, subscriber_factory) = pubsub_channel(1);
+        let _critical = subscriber_factory.get_subscription_with_policy("Critical", OverflowPolicy::Block);
+        block_on(publisher.publish(TopicPayload::new("Critical", 1))).unwrap();
+
+        let blocked_publisher = publisher.clone();
+        let (result_tx, result_rx) = std::sync::mpsc::channel();
+        let handle = thread::spawn(move || {
+            let result = block_on(blocked_publisher.publish(TopicPayload::new("Critical", 2)));
+            result_tx.send(result).unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(result_rx.try_recv().is_err());
+
+        publisher.close();
+        assert_eq!(
+            result_rx.recv_timeout(Duration::from_secs(5)),
+            Ok(Err(PubSubError::ChannelClosed))
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn removing_a_topic_releases_blocked_publishers() {
+        let config = PubSubConfig::new(1).with_overflow_policy(OverflowPolicy::Block);
+        let (publisher, subscriber_factory) = pubsub_channel_with_config(config);
+        let subscription = subscriber_factory.get_dynamic_subscription(vec!["A", "B"]);
+        block_on(publisher.publish(TopicPayload::new("A", 1))).unwrap();
+
+        let blocked_publisher = publisher.clone();
+        let (result_tx, result_rx) = std::sync::mpsc::channel();
+        let handle = thread::spawn(move || {
+            let result = block_on(blocked_publisher.publish(TopicPayload::new("A", 2)));
+            result_tx.send(result).unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(result_rx.try_recv().is_err());
+
+        // The subscription is still full and holds back "B", but no longer receives "A"
+        assert!(subscription.remove_topic(&"A"));
+        assert_eq!(result_rx.recv_timeout(Duration::from_secs(5)), Ok(Ok(())));
+        assert_eq!(
+            publisher.try_publish(TopicPayload::new("B", 3)),
+            Err(PubSubError::BufferFull)
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn delivery_report() {
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let report = block_on(publisher.publish_with_report(TopicPayload::new("Critical", 1))).unwrap();
+        assert_eq!(report.matched, 0);
+
+        let _sub1 = subscriber_factory.get_subscription("Critical");
+        let _sub2 = subscriber_factory.get_subscription_with_capacity("Critical", 1);
+        let report = block_on(publisher.publish_with_report(TopicPayload::new("Critical", 2))).unwrap();
+        assert_eq!(report, DeliveryReport {
+            matched: 2,
+            enqueued: 2,
+            evicted: 1,
+            dropped: 0,
+        });
+    }
+
+    #[test]
+    fn lazy_publishing() {
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let mut num_built = 0;
+
+        assert!(!publisher.has_subscribers(&"BlockSummary"));
+        let result = block_on(publisher.publish_with("BlockSummary", || {
+            num_built += 1;
+            "summary 1".to_string()
+        }));
+        assert_eq!(result, Err(PubSubError::NoSubscribers));
+        assert_eq!(num_built, 0);
+
+        let subscription = subscriber_factory.get_subscription("BlockSummary");
+        assert!(publisher.has_subscribers(&"BlockSummary"));
+        block_on(publisher.publish_with("BlockSummary", || {
+            num_built += 1;
+            "summary 2".to_string()
+        }))
+        .unwrap();
+        assert_eq!(num_built, 1);
+
+        drop(publisher);
+        assert_eq!(
+            block_on(subscription.collect::<Vec<_>>()),
+            vec!["summary 2".to_string()]
+        );
+    }
+
+    #[test]
+    fn subscription_handles() {
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let blocks = subscriber_factory.get_subscription("Block");
+        let everything = subscriber_factory.get_subscription_where(|_, _| true);
+        assert_ne!(blocks.id(), everything.id());
+        assert_eq!(blocks.topics(), vec!["Block"]);
+        assert!(everything.topics().is_empty());
+        assert_eq!(subscriber_factory.num_subscriptions(), 2);
+
+        block_on(publisher.publish(TopicPayload::new("Block", 1))).unwrap();
+        assert!(blocks.unsubscribe());
+        assert_eq!(subscriber_factory.num_subscriptions(), 1);
+        drop(everything);
+        assert_eq!(subscriber_factory.num_subscriptions(), 0);
+        assert!(!publisher.has_subscribers(&"Block"));
+
+        block_on(publisher.publish(TopicPayload::new("Block", 2))).unwrap();
+        // The publisher is still live, but the unsubscribed stream ends after yielding what it had buffered
+        assert_eq!(block_on(blocks.collect::<Vec<_>>()), vec![1]);
+    }
+
+    #[test]
+    fn subscription_capacities() {
+        let (mut publisher, subscriber_factory) = pubsub_channel(2);
+        let low_volume = subscriber_factory.get_subscription_with_capacity("LowVolume", 1);
+        let high_volume = subscriber_factory.get_subscription_with_capacity("HighVolume", 50);
+        let default = subscriber_factory.get_subscription("HighVolume");
+
+        block_on(async {
+            publisher.send(TopicPayload::new("LowVolume", 1000)).await.unwrap();
+            for i in 0..50 {
+                publisher.send(TopicPayload::new("HighVolume", i)).await.unwrap();
+            }
+        });
+        drop(publisher);
+
+        // A high-volume topic does not evict messages of other subscriptions
+        assert_eq!(block_on(low_volume.collect::<Vec<u32>>()), vec![1000]);
+        assert_eq!(block_on(high_volume.collect::<Vec<u32>>()), (0..50).collect::<Vec<_>>());
+        assert_eq!(block_on(default.collect::<Vec<u32>>()), vec![48, 49]);
+    }
+
+    #[test]
+    fn lagged_subscription() {
+        let (mut publisher, subscriber_factory) = pubsub_channel(3);
+        let lagging = subscriber_factory.get_subscription_with_lag("Topic1");
+        let lossy = subscriber_factory.get_subscription("Topic1");
+
+        block_on(async {
+            for i in 0..5 {
+                publisher.send(TopicPayload::new("Topic1", i)).await.unwrap();
+            }
+        });
+        drop(publisher);
+
+        let items = block_on(lagging.collect::<Vec<_>>());
+        assert_eq!(items, vec![Err(Lagged { missed: 2 }), Ok(2), Ok(3), Ok(4)]);
+        let items = block_on(lossy.collect::<Vec<u32>>());
+        assert_eq!(items, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let subscription = subscriber_factory.get_subscription("Topic1");
+        let idle = subscriber_factory.get_subscription("Topic2");
+        block_on(async {
+            for i in 0..3 {
+                publisher.publish(TopicPayload::new("Topic1", i)).await.unwrap();
+            }
+        });
+
+        let shutdown = publisher.shutdown(Duration::from_secs(10));
+        assert_eq!(
+            publisher.try_publish(TopicPayload::new("Topic1", 3)),
+            Err(PubSubError::ChannelClosed)
+        );
+        let consumer = thread::spawn(move || block_on(subscription.collect::<Vec<_>>()));
+        assert_eq!(block_on(shutdown), Ok(()));
+        assert_eq!(consumer.join().unwrap(), vec![0, 1, 2]);
+        drop(idle);
+
+        // A subscription that never consumes its backlog holds up the shutdown until the deadline
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let _stalled = subscriber_factory.get_subscription("Topic1");
+        block_on(publisher.publish(TopicPayload::new("Topic1", 0))).unwrap();
+        assert_eq!(
+            block_on(publisher.shutdown(Duration::from_millis(10))),
+            Err(PubSubError::Timeout)
+        );
+    }
+
+    #[test]
+    fn lifecycle_events() {
+        let (publisher, subscriber_factory) = pubsub_channel(2);
+        let mempool = subscriber_factory.get_subscription("Mempool");
+        let events = subscriber_factory.get_lifecycle_events();
+
+        let mut blocks = subscriber_factory.get_subscription_with_lag("Block");
+        let everything = subscriber_factory.get_subscription_where(|_, _| true);
+        block_on(async {
+            for i in 0..3 {
+                publisher.publish(TopicPayload::new("Block", i)).await.unwrap();
+            }
+        });
+        assert_eq!(block_on(blocks.next()), Some(Err(Lagged { missed: 1 })));
+        mempool.unsubscribe();
+        drop(everything);
+        drop(publisher);
+
+        let mempool_id = mempool.id();
+        let blocks_id = blocks.id();
+        let events = block_on(events.collect::<Vec<_>>());
+        assert_eq!(events[0], LifecycleEvent::Subscribed {
+            id: mempool_id,
+            topic: Some("Mempool")
+        });
+        assert_eq!(events[1], LifecycleEvent::Subscribed {
+            id: blocks_id,
+            topic: Some("Block")
+        });
+        assert!(matches!(events[2], LifecycleEvent::Subscribed { topic: None, .. }));
+        assert_eq!(events[3], LifecycleEvent::Lagged {
+            id: blocks_id,
+            missed: 1
+        });
+        assert!(matches!(events[4], LifecycleEvent::Lagged { missed: 1, .. }));
+        assert_eq!(events[5], LifecycleEvent::Unsubscribed {
+            id: mempool_id,
+            topic: Some("Mempool")
+        });
+        assert!(matches!(events[6], LifecycleEvent::Unsubscribed { topic: None, .. }));
+        assert_eq!(events[7..], [LifecycleEvent::Closed]);
+    }
+
+    #[test]
+    fn lifecycle_events_report_stalled_subscriptions() {
+        let (publisher, subscriber_factory) = pubsub_channel(2);
+        let mut mempool = subscriber_factory.get_subscription_with_lag("Mempool");
+        let mut events = subscriber_factory.get_lifecycle_events();
+        let id = mempool.id();
+        block_on(async {
+            assert_eq!(
+                events.next().await,
+                Some(LifecycleEvent::Subscribed {
+                    id,
+                    topic: Some("Mempool")
+                })
+            );
+            // A subscription that has stopped polling is reported once, when it first drops a message
+            for i in 0..10 {
+                publisher.publish(TopicPayload::new("Mempool", i)).await.unwrap();
+            }
+            assert_eq!(events.next().await, Some(LifecycleEvent::Lagged { id, missed: 1 }));
+
+            // Once it reaches the gap, the next dropped message is reported with those dropped in the meantime
+            assert_eq!(mempool.next().await, Some(Err(Lagged { missed: 8 })));
+            publisher.publish(TopicPayload::new("Mempool", 10)).await.unwrap();
+            assert_eq!(events.next().await, Some(LifecycleEvent::Lagged { id, missed: 8 }));
+        });
+    }
+
+    #[test]
+    fn channel_stats() {
+        let (publisher, subscriber_factory) = pubsub_channel_with_id(2, 7);
+        let mut fast = subscriber_factory.get_subscription("Block");
+        let slow = subscriber_factory.get_subscription("Block");
+        block_on(async {
+            for i in 0..2 {
+                publisher.publish(TopicPayload::new("Block", i)).await.unwrap();
+                assert_eq!(fast.next().await, Some(i));
+            }
+            publisher.publish(TopicPayload::new("Block", 2)).await.unwrap();
+            publisher.publish(TopicPayload::new("Mempool", 0)).await.unwrap();
+        });
+
+        let stats = subscriber_factory.stats();
+        assert_eq!(stats.receiver_id, 7);
+        assert_eq!(stats.topics["Block"], TopicStats {
+            published: 3,
+            delivered: 2,
+            dropped: 1,
+        });
+        assert_eq!(stats.topics["Mempool"], TopicStats {
+            published: 1,
+            delivered: 0,
+            dropped: 0,
+        });
+        assert_eq!(stats.subscriptions.len(), 2);
+        assert_eq!(stats.subscriptions[0], SubscriptionStats {
+            id: fast.id(),
+            topics: vec!["Block"],
+            delivered: 2,
+            dropped: 0,
+            queue_depth: 1,
+            max_queue_depth: 1,
+        });
+        assert_eq!(stats.subscriptions[1], SubscriptionStats {
+            id: slow.id(),
+            topics: vec!["Block"],
+            delivered: 0,
+            dropped: 1,
+            queue_depth: 2,
+            max_queue_depth: 2,
+        });
+
+        subscriber_factory.reset_stats();
+        let stats = subscriber_factory.stats();
+        assert!(stats.topics.is_empty());
+        assert_eq!(stats.subscriptions[1], SubscriptionStats {
+            id: slow.id(),
+            topics: vec!["Block"],
+            delivered: 0,
+            dropped: 0,
+            queue_depth: 2,
+            max_queue_depth: 2,
+        });
+
+        let (publisher, subscriber_factory) = pubsub_channel_with_config(PubSubConfig::new(2).with_topic_stats(false));
+        let mut sub = subscriber_factory.get_subscription("Block");
+        block_on(async {
+            publisher.publish(TopicPayload::new("Block", 0)).await.unwrap();
+            assert_eq!(sub.next().await, Some(0));
+        });
+        let stats = subscriber_factory.stats();
+        assert!(stats.topics.is_empty());
+        assert_eq!(stats.subscriptions[0].delivered, 1);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_spans() {
+        use std::sync::Mutex;
+
+        use tracing::{span, Event, Id, Subscriber};
+        use tracing_subscriber::{
+            layer::{Context, SubscriberExt},
+            registry::LookupSpan,
+            Layer,
+        };
+
+        /// Records each span with the name of its parent, each event with the name of the span it occurred in, and when
+        /// each span closes
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+            fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+                let parent = ctx
+                    .span(id)
+                    .and_then(|s| s.parent())
+                    .map(|p| p.name())
+                    .unwrap_or("none");
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("span {} in {}", attrs.metadata().name(), parent));
+            }
+
+            fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+                let scope = ctx.event_span(event).map(|s| s.name()).unwrap_or("none");
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} in {}", event.metadata().level(), scope));
+            }
+
+            fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+                let name = ctx.span(&id).map(|s| s.name()).unwrap_or("none");
+                self.0.lock().unwrap().push(format!("close {}", name));
+            }
+        }
+
+        let recorder = Recorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let (publisher, subscriber_factory) = pubsub_channel(1);
+            let mut subscription = subscriber_factory.get_subscription_with_topic("Block");
+            tracing::info_span!("mine_block").in_scope(|| {
+                publisher.try_publish(TopicPayload::new("Block", 1)).unwrap();
+                publisher.try_publish(TopicPayload::new("Block", 2)).unwrap();
+            });
+            let payload = block_on(subscription.next()).unwrap();
+            assert_eq!(payload.into_message(), 2);
+            subscription.span().in_scope(|| tracing::info!("Block handled"));
+
+            // The message is still held to backfill new subscriptions, but that does not keep the publisher's span open
+            drop(subscription);
+            let late = subscriber_factory.get_subscription("Block");
+            assert_eq!(late.span().id(), None);
+            assert!(recorder
+                .0
+                .lock()
+                .unwrap()
+                .ends_with(&["close pubsub_deliver".to_string(), "close mine_block".to_string()]));
+        });
+
+        assert_eq!(recorder.0.lock().unwrap()[..7], [
+            "span mine_block in none",
+            "DEBUG in mine_block",
+            "DEBUG in none",
+            "span pubsub_deliver in mine_block",
+            "TRACE in pubsub_deliver",
+            "INFO in pubsub_deliver",
+            "close pubsub_deliver",
+        ]);
+    }
+
+    #[test]
+    fn payload_metadata() {
+        let (publisher, subscriber_factory) = pubsub_channel(2);
+        let other_publisher = publisher.clone();
+        assert_ne!(publisher.id(), other_publisher.id());
+        let envelopes = subscriber_factory.get_envelope_subscription("Block");
+
+        let before = SystemTime::now();
+        block_on(async {
+            publisher
+                .publish(
+                    TopicPayload::new("Block", 1)
+                        .with_correlation_id("req-1")
+                        .with_header("origin", "miner"),
+                )
+                .await
+                .unwrap();
+            other_publisher.publish(TopicPayload::new("Mempool", 2)).await.unwrap();
+            for i in 3..6 {
+                other_publisher.publish(TopicPayload::new("Block", i)).await.unwrap();
+            }
+        });
+        drop(publisher);
+        drop(other_publisher);
+
+        let envelopes = block_on(envelopes.collect::<Vec<_>>());
+        assert_eq!(envelopes.len(), 3);
+        assert_eq!(envelopes[0].as_ref().unwrap_err(), &Lagged { missed: 2 });
+        let received = envelopes[1..].iter().map(|e| e.as_ref().unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            received.iter().map(|p| p.metadata().sequence()).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert!(received.iter().all(|p| p.metadata().timestamp() >= before));
+        assert!(received.iter().all(|p| p.metadata().correlation_id().is_none()));
+
+        // A subscription created later is backfilled with the message still in the channel's history
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        block_on(
+            publisher.publish(
+                TopicPayload::new("Block", 1)
+                    .with_correlation_id("req-1")
+                    .with_header("origin", "miner"),
+            ),
+        )
+        .unwrap();
+        let publisher_id = publisher.id();
+        drop(publisher);
+        let payload = block_on(subscriber_factory.get_envelope_subscription("Block").next())
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload.metadata().publisher_id(), Some(publisher_id));
+        assert_eq!(payload.metadata().correlation_id(), Some("req-1"));
+        assert_eq!(payload.metadata().header("origin"), Some("miner"));
+        assert_eq!(payload.metadata().header("missing"), None);
+        assert_eq!(payload.metadata().sequence(), 0);
+    }
+
+    #[test]
+    fn request_reply() {
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let responder = Responder::new(publisher.clone(), &subscriber_factory, "Double");
+        let requester = Requester::new(
+            publisher.clone(),
+            &subscriber_factory,
+            "Replies",
+            Duration::from_secs(10),
+        );
+        let impatient = Requester::new(
+            publisher.clone(),
+            &subscriber_factory,
+            "Replies",
+            Duration::from_millis(10),
+        );
+        let server = thread::spawn(move || block_on(responder.run(|n: u32| future::ready(n * 2))));
+
+        assert_eq!(block_on(requester.request("Double", 21)), Ok(42));
+        assert_eq!(block_on(requester.request("Double", 5)), Ok(10));
+        assert_eq!(block_on(impatient.request("Nobody", 1)), Err(PubSubError::Timeout));
+
+        // Abandoned requests stop listening for their replies. The reply subscriptions are internal, so they are not
+        // counted as subscriptions of the channel.
+        let events = subscriber_factory.get_lifecycle_events();
+        let replies = || publisher.publish_with_report(TopicPayload::new("Replies", 0));
+        block_on(async {
+            let mut abandoned = Box::pin(requester.request("Nobody", 2));
+            assert!(futures::poll!(&mut abandoned).is_pending());
+            assert_eq!(subscriber_factory.num_subscriptions(), 1);
+            assert_eq!(replies().await.unwrap().matched, 1);
+        });
+        assert_eq!(block_on(replies()).unwrap().matched, 0);
+
+        publisher.close();
+        server.join().unwrap();
+        let events = block_on(events.collect::<Vec<_>>());
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], LifecycleEvent::Subscribed {
+            topic: Some("Double"),
+            ..
+        }));
+    }
+
+    #[test]
+    fn responder_serves_only_new_requests() {
+        let (publisher, subscriber_factory) = pubsub_channel(1);
+        let mut blocker = subscriber_factory.get_subscription_with_policy("Replies", OverflowPolicy::Fail);
+        let stale = TopicPayload::new("Double", 50)
+            .with_reply_to("Replies")
+            .with_correlation_id("stale");
+        block_on(publisher.publish(stale)).unwrap();
+
+        let responder = Responder::new(publisher.clone(), &subscriber_factory, "Double");
+        let requester = Requester::new(
+            publisher.clone(),
+            &subscriber_factory,
+            "Replies",
+            Duration::from_millis(100),
+        );
+        let server = thread::spawn(move || block_on(responder.run(|n: u32| future::ready(n * 2))));
+
+        // The stale request is not answered, so the first reply fills the blocker's buffer
+        assert_eq!(block_on(requester.request("Double", 1)), Ok(2));
+        // The next reply is rejected by the full blocker, but the responder keeps serving requests
+        assert_eq!(block_on(requester.request("Double", 2)), Err(PubSubError::Timeout));
+        assert_eq!(block_on(blocker.next()), Some(2));
+        assert_eq!(block_on(requester.request("Double", 3)), Ok(6));
+
+        publisher.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn scatter_gather() {
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let servers = (1..=3u32)
+            .map(|peer| {
+                let responder = Responder::new(publisher.clone(), &subscriber_factory, "Peers");
+                thread::spawn(move || block_on(responder.run(move |n: u32| future::ready(n * peer))))
+            })
+            .collect::<Vec<_>>();
+        let requester = Requester::new(
+            publisher.clone(),
+            &subscriber_factory,
+            "Replies",
+            Duration::from_secs(10),
+        );
+
+        // Gathering ends as soon as every responder has replied, well before the deadline
+        let mut replies = block_on(async {
+            let replies = requester.scatter("Peers", 10, GatherLimits::new()).await.unwrap();
+            replies.collect::<Vec<_>>().await
+        });
+        replies.sort_unstable();
+        assert_eq!(replies, vec![10, 20, 30]);
+
+        let replies = block_on(async {
+            let limits = GatherLimits::new().with_max_replies(2);
+            let replies = requester.scatter("Peers", 1, limits).await.unwrap();
+            replies.collect::<Vec<_>>().await
+        });
+        assert_eq!(replies.len(), 2);
+
+        // Other subscriptions to the topic are not responders and are not waited for
+        let _listener = subscriber_factory.get_subscription("Peers");
+        let _audit = subscriber_factory.get_subscription_where(|_, _| true);
+        let started = Instant::now();
+        let replies = block_on(async {
+            let replies = requester.scatter("Peers", 1, GatherLimits::new()).await.unwrap();
+            replies.collect::<Vec<_>>().await
+        });
+        assert_eq!(replies.len(), 3);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        publisher.close();
+        servers.into_iter().for_each(|s| s.join().unwrap());
+    }
+
+    #[test]
+    fn scatter_gather_with_lost_replies() {
+        let policies = [
+            OverflowPolicy::DropOldest,
+            OverflowPolicy::DropNewest,
+            OverflowPolicy::Block,
+            OverflowPolicy::Fail,
+        ];
+        for policy in policies.iter().copied() {
+            let config = PubSubConfig::new(2).with_overflow_policy(policy);
+            let (publisher, subscriber_factory) = pubsub_channel_with_config(config);
+            let servers = (1..=3u32)
+                .map(|peer| {
+                    let responder = Responder::new(publisher.clone(), &subscriber_factory, "Peers");
+                    thread::spawn(move || block_on(responder.run(move |n: u32| future::ready(n * peer))))
+                })
+                .collect::<Vec<_>>();
+            let requester = Requester::new(
+                publisher.clone(),
+                &subscriber_factory,
+                "Replies",
+                Duration::from_secs(10),
+            );
+
+            // Only two of the three replies fit in the buffer, whatever the channel's overflow policy, and the dropped
+            // reply is not waited for
+            let started = Instant::now();
+            let replies = block_on(async {
+                let replies = requester.scatter("Peers", 10, GatherLimits::new()).await.unwrap();
+                thread::sleep(Duration::from_millis(200));
+                replies.collect::<Vec<_>>().await
+            });
+            assert_eq!(replies.len(), 2, "{:?}", policy);
+            assert!(started.elapsed() < Duration::from_secs(5), "{:?}", policy);
+
+            publisher.close();
+            servers.into_iter().for_each(|s| s.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn scatter_gather_without_responders() {
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let mut requests = subscriber_factory.get_envelope_subscription("Peers");
+        let replier = publisher.clone();
+        let server = thread::spawn(move || {
+            block_on(async {
+                while let Some(Ok(request)) = requests.next().await {
+                    let reply = TopicPayload::new(*request.reply_to().unwrap(), request.message() * 7)
+                        .with_correlation_id(request.metadata().correlation_id().unwrap());
+                    replier.publish(reply).await.unwrap();
+                }
+            })
+        });
+        let requester = Requester::new(
+            publisher.clone(),
+            &subscriber_factory,
+            "Replies",
+            Duration::from_secs(10),
+        );
+
+        // Replies from subscriptions that are not responders are gathered until the deadline or the maximum
+        let started = Instant::now();
+        let replies = block_on(async {
+            let limits = GatherLimits::new().with_timeout(Duration::from_millis(200));
+            let replies = requester.scatter("Peers", 2, limits).await.unwrap();
+            replies.collect::<Vec<_>>().await
+        });
+        assert_eq!(replies, vec![14]);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let replies = block_on(async {
+            let limits = GatherLimits::new().with_max_replies(1);
+            let replies = requester.scatter("Peers", 3, limits).await.unwrap();
+            replies.collect::<Vec<_>>().await
+        });
+        assert_eq!(replies, vec![21]);
+
+        publisher.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn queue_groups() {
+        let (publisher, subscriber_factory) = pubsub_channel(10);
+        let workers = (0..3)
+            .map(|_| subscriber_factory.get_queue_subscription("Tx", "validators", GroupSelection::RoundRobin))
+            .collect::<Vec<_>>();
+        let auditor = subscriber_factory.get_subscription("Tx");
+        block_on(async {
+            for i in 0..6 {
+                publisher.publish(TopicPayload::new("Tx", i)).await.unwrap();
+            }
+        });
+        drop(publisher);
+
+        let received = workers
+            .into_iter()
+            .map(|worker| block_on(worker.collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec![vec![0, 3], vec![1, 4], vec![2, 5]]);
+        // Subscriptions outside the group still receive every message
+        assert_eq!(block_on(auditor.collect::<Vec<_>>()), (0..6).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn multiple_producers() {
+        let (publisher, subscriber_factory) = pubsub_channel(100);
+        let subscription = subscriber_factory.get_subscription("Topic1");
+
+        let handles = (0..4u32)
+            .map(|producer| {
+                let mut publisher = publisher.clone();
+                thread::spawn(move || {
+                    block_on(async {
+                        for i in 0..10 {
+                            publisher
+                                .send(TopicPayload::new("Topic1", (producer, i)))
+                                .await
+                                .unwrap();
+                        }
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+
+        // The channel stays open until the original handle is dropped as well
+        let mut subscription = subscription.fuse();
+        let received = block_on(async {
+            let mut result = Vec::new();
+            loop {
+                futures::select!(
+                    item = subscription.select_next_some() => result.push(item),
+                    default => break,
+                );
+            }
+            result
+        });
+        assert_eq!(received.len(), 40);
+        for producer in 0..4 {
+            let sequence = received
+                .iter()
+                .filter(|(p, _)| *p == producer)
+                .map(|(_, i)| *i)
+                .collect::<Vec<_>>();
+            assert_eq!(sequence, (0..10).collect::<Vec<_>>());
+        }
+
+        drop(publisher);
+        assert!(block_on(subscription.next()).is_none());
+    }
+
+    #[test]
+    fn retained_messages() {
+        let (mut publisher, subscriber_factory) = pubsub_channel(2);
+
+        block_on(async {
+            publisher
+                .send(TopicPayload::new_retained("ChainTip", 100))
+                .await
+                .unwrap();
+            publisher.send(TopicPayload::new("ChainTip", 101)).await.unwrap();
+            publisher.send(TopicPayload::new("Other", 1)).await.unwrap();
+            publisher.send(TopicPayload::new("Other", 2)).await.unwrap();
+        });
+
+        // The retained tip has left the buffer but is still delivered to a late subscriber
+        let tip = subscriber_factory.get_subscription("ChainTip");
+        block_on(async {
+            publisher
+                .send(TopicPayload::new_retained("ChainTip", 102))
+                .await
+                .unwrap();
+        });
+        let late_tip = subscriber_factory.get_subscription("ChainTip");
+        publisher.clear_retained(&"ChainTip");
+        let cleared_tip = subscriber_factory.get_subscription("ChainTip");
+        drop(publisher);
+
+        assert_eq!(block_on(tip.collect::<Vec<u32>>()), vec![100, 102]);
+        assert_eq!(block_on(late_tip.collect::<Vec<u32>>()), vec![102]);
+        // The cleared message is not backfilled even though it is still among the recent messages
+        assert!(block_on(cleared_tip.collect::<Vec<u32>>()).is_empty());
+    }
 }
@@ -0,0 +1,41 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use crate::SubscriptionId;
+
+/// An event in the lifecycle of a channel's subscriptions, yielded by the stream returned from
+/// `TopicSubscriptionFactory::get_lifecycle_events`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifecycleEvent<T> {
+    /// A subscription started receiving messages of a topic. The topic is `None` for subscriptions that match topics
+    /// by pattern or predicate.
+    Subscribed { id: SubscriptionId, topic: Option<T> },
+    /// A subscription stopped receiving messages of a topic, because it was unsubscribed, dropped or removed the
+    /// topic. The topic is `None` for subscriptions that match topics by pattern or predicate.
+    Unsubscribed { id: SubscriptionId, topic: Option<T> },
+    /// A subscription fell behind and dropped messages because its buffer was full. This is emitted when the
+    /// subscription drops a message, whether or not it is still being polled, and at most once until the subscription
+    /// reaches a gap in its buffer. `missed` is the number of messages it has dropped since the previous `Lagged`
+    /// event about it.
+    Lagged { id: SubscriptionId, missed: u64 },
+    /// The channel was closed, no further events follow
+    Closed,
+}
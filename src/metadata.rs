@@ -0,0 +1,91 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Identifies a publisher handle within its channel. Every clone of a `TopicPublisher` has its own id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PublisherId(pub(crate) u64);
+
+impl fmt::Display for PublisherId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Metadata carried by a `TopicPayload`. The sequence number, timestamp and publisher id are assigned by the channel
+/// when the payload is published, while the correlation id and headers are set by the publisher.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub(crate) sequence: u64,
+    pub(crate) timestamp: SystemTime,
+    pub(crate) publisher_id: Option<PublisherId>,
+    pub(crate) correlation_id: Option<String>,
+    pub(crate) headers: HashMap<String, String>,
+}
+
+impl Metadata {
+    /// The position of the payload in the order in which messages were published to the channel. Sequence numbers
+    /// increase monotonically across all topics of a channel.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The time at which the payload was published
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// The id of the publisher handle that published the payload, if it was published through a `TopicPublisher`
+    pub fn publisher_id(&self) -> Option<PublisherId> {
+        self.publisher_id
+    }
+
+    /// An id that relates the payload to other messages, e.g. a request and its replies
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    /// Returns the value of the given header
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self {
+            sequence: 0,
+            timestamp: UNIX_EPOCH,
+            publisher_id: None,
+            correlation_id: None,
+            headers: HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! Rendering of channel statistics in the Prometheus text exposition format, enabled with the `prometheus` feature
+
+use std::fmt::{Display, Write};
+
+use crate::ChannelStats;
+
+const TOPIC_METRICS: [(&str, &str); 3] = [
+    ("tari_pubsub_published_total", "Messages published to the topic"),
+    (
+        "tari_pubsub_delivered_total",
+        "Messages of the topic yielded to subscriptions",
+    ),
+    (
+        "tari_pubsub_dropped_total",
+        "Messages of the topic dropped by full subscriptions",
+    ),
+];
+
+const SUBSCRIPTION_METRICS: [(&str, &str); 3] = [
+    ("tari_pubsub_subscriptions", "Live subscriptions"),
+    (
+        "tari_pubsub_queued_messages",
+        "Messages currently buffered for all subscriptions",
+    ),
+    (
+        "tari_pubsub_max_queue_depth",
+        "Largest number of messages buffered for a live subscription at once",
+    ),
+];
+
+impl<T: Display> ChannelStats<T> {
+    /// Render the statistics in the Prometheus text exposition format. Topic metrics are labelled by the channel's
+    /// `receiver_id` and the topic, and subscription metrics are aggregated over the channel's subscriptions and
+    /// labelled by the `receiver_id` only, because subscription ids change whenever a subscription is created. Use
+    /// `render_prometheus` to expose the statistics of several channels together.
+    ///
+    /// Every topic that has been published to adds a series to each topic metric. For channels whose topics are not
+    /// a fixed set, e.g. topics that include an id, disable the topic counters with `PubSubConfig::with_topic_stats`
+    /// or reset them with `TopicSubscriptionFactory::reset_stats` to bound the number of series.
+    pub fn to_prometheus(&self) -> String {
+        render_prometheus(Some(self))
+    }
+}
+
+/// Render the statistics of several channels in the Prometheus text exposition format, as with
+/// `ChannelStats::to_prometheus`. Each metric is described once and followed by the series of every channel, which are
+/// told apart by their `receiver_id`, since the text format does not allow a metric to be described more than once.
+pub fn render_prometheus<'a, T, I>(channels: I) -> String
+where
+    T: Display + 'a,
+    I: IntoIterator<Item = &'a ChannelStats<T>>,
+{
+    let channels = channels
+        .into_iter()
+        .map(|channel| {
+            let mut topics = channel
+                .topics
+                .iter()
+                .map(|(topic, stats)| (escape_label(&topic.to_string()), stats))
+                .collect::<Vec<_>>();
+            topics.sort_by(|(a, _), (b, _)| a.cmp(b));
+            (channel, topics)
+        })
+        .collect::<Vec<_>>();
+
+    let mut out = String::new();
+    for (i, (name, help)) in TOPIC_METRICS.iter().enumerate() {
+        write_header(&mut out, name, "counter", help);
+        for (channel, topics) in &channels {
+            for (topic, stats) in topics {
+                let value = [stats.published, stats.delivered, stats.dropped][i];
+                let _ = writeln!(
+                    out,
+                    "{}{{receiver_id=\"{}\",topic=\"{}\"}} {}",
+                    name, channel.receiver_id, topic, value
+                );
+            }
+        }
+    }
+    for (i, (name, help)) in SUBSCRIPTION_METRICS.iter().enumerate() {
+        write_header(&mut out, name, "gauge", help);
+        for (channel, _) in &channels {
+            let subscriptions = &channel.subscriptions;
+            let value = [
+                subscriptions.len(),
+                subscriptions.iter().map(|stats| stats.queue_depth).sum(),
+                subscriptions
+                    .iter()
+                    .map(|stats| stats.max_queue_depth)
+                    .max()
+                    .unwrap_or_default(),
+            ][i];
+            let _ = writeln!(out, "{}{{receiver_id=\"{}\"}} {}", name, channel.receiver_id, value);
+        }
+    }
+    out
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value as required by the text format
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{executor::block_on, StreamExt};
+
+    use super::render_prometheus;
+    use crate::{pubsub_channel_with_id, TopicPayload};
+
+    #[test]
+    fn renders_topic_and_subscription_metrics() {
+        let (publisher, subscriber_factory) = pubsub_channel_with_id(10, 3);
+        let mut subscription = subscriber_factory.get_subscription("wallet/\"main\"");
+        let _idle = subscriber_factory.get_subscription("block");
+        block_on(async {
+            publisher
+                .publish(TopicPayload::new("wallet/\"main\"", 1))
+                .await
+                .unwrap();
+            publisher.publish(TopicPayload::new("block", 2)).await.unwrap();
+            subscription.next().await.unwrap();
+        });
+
+        let text = subscriber_factory.stats().to_prometheus();
+        let samples = text.lines().filter(|l| !l.starts_with('#')).collect::<Vec<_>>();
+        assert_eq!(samples, vec![
+            r#"tari_pubsub_published_total{receiver_id="3",topic="block"} 1"#,
+            r#"tari_pubsub_published_total{receiver_id="3",topic="wallet/\"main\""} 1"#,
+            r#"tari_pubsub_delivered_total{receiver_id="3",topic="block"} 0"#,
+            r#"tari_pubsub_delivered_total{receiver_id="3",topic="wallet/\"main\""} 1"#,
+            r#"tari_pubsub_dropped_total{receiver_id="3",topic="block"} 0"#,
+            r#"tari_pubsub_dropped_total{receiver_id="3",topic="wallet/\"main\""} 0"#,
+            r#"tari_pubsub_subscriptions{receiver_id="3"} 2"#,
+            r#"tari_pubsub_queued_messages{receiver_id="3"} 1"#,
+            r#"tari_pubsub_max_queue_depth{receiver_id="3"} 1"#,
+        ]);
+        assert!(text.contains("# TYPE tari_pubsub_queued_messages gauge\n"));
+    }
+
+    #[test]
+    fn renders_several_channels_under_one_header() {
+        let (publisher, subscriber_factory) = pubsub_channel_with_id(10, 1);
+        let (other_publisher, other_factory) = pubsub_channel_with_id(10, 2);
+        let _subscription = other_factory.get_subscription("block");
+        block_on(async {
+            publisher.publish(TopicPayload::new("block", 1)).await.unwrap();
+            other_publisher.publish(TopicPayload::new("block", 2)).await.unwrap();
+        });
+
+        let text = render_prometheus(&[subscriber_factory.stats(), other_factory.stats()]);
+        assert_eq!(text.matches("# TYPE tari_pubsub_published_total counter").count(), 1);
+        let samples = text
+            .lines()
+            .filter(|l| l.starts_with("tari_pubsub_published_total") || l.starts_with("tari_pubsub_subscriptions"))
+            .collect::<Vec<_>>();
+        assert_eq!(samples, vec![
+            r#"tari_pubsub_published_total{receiver_id="1",topic="block"} 1"#,
+            r#"tari_pubsub_published_total{receiver_id="2",topic="block"} 1"#,
+            r#"tari_pubsub_subscriptions{receiver_id="1"} 0"#,
+            r#"tari_pubsub_subscriptions{receiver_id="2"} 1"#,
+        ]);
+    }
+}
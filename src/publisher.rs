@@ -0,0 +1,197 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use std::{
+    hash::Hash,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    future::{self, Either},
+    Future,
+    FutureExt,
+    Sink,
+};
+use futures_timer::Delay;
+
+use crate::{dispatcher::Dispatcher, PubSubError, PublisherId, TopicPayload};
+
+/// Describes how a published message was delivered to the channel's subscriptions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeliveryReport {
+    /// The number of subscriptions whose topics matched the message's topic. This includes subscriptions whose
+    /// content predicate rejected the message.
+    pub matched: usize,
+    /// The number of subscriptions that buffered the message
+    pub enqueued: usize,
+    /// The number of subscriptions that buffered the message by evicting their oldest buffered message
+    pub evicted: usize,
+    /// The number of subscriptions that dropped the message because their buffer was full
+    pub dropped: usize,
+}
+
+/// The publishing end of a pub-sub channel. Messages are published with `publish` or `try_publish`, or sent using the
+/// `Sink` interface, and are routed only to the subscriptions interested in their topic.
+///
+/// The publisher can be cloned to publish to the same channel from several tasks. Messages sent through a single handle
+/// are delivered in the order they were sent. The channel is closed once every publisher handle has been dropped, or
+/// when any handle calls `close`.
+///
+/// Publishing a message waits while a subscription of its topic with the `Block` overflow policy has a full buffer,
+/// and fails with `PubSubError::RejectedByPolicy` if the message is routed to a full subscription with the `Fail`
+/// overflow policy. The `Sink` interface cannot know the topic of the next message in advance, so it waits while any
+/// blocking subscription is full.
+pub struct TopicPublisher<T, M>
+where T: Hash + Eq + Clone
+{
+    dispatcher: Arc<Dispatcher<T, M>>,
+    id: PublisherId,
+}
+
+impl<T, M> TopicPublisher<T, M>
+where T: Hash + Eq + Clone
+{
+    pub(crate) fn new(dispatcher: Arc<Dispatcher<T, M>>) -> Self {
+        let id = dispatcher.add_publisher();
+        Self { dispatcher, id }
+    }
+
+    /// Returns the id of this publisher handle, which is recorded in the metadata of every payload it publishes
+    pub fn id(&self) -> PublisherId {
+        self.id
+    }
+
+    /// Publish a message, waiting until every subscription of its topic with the `Block` overflow policy has room in
+    /// its buffer
+    pub async fn publish(&self, payload: TopicPayload<T, M>) -> Result<(), PubSubError> {
+        self.publish_with_report(payload).await.map(|_| ())
+    }
+
+    /// Publish a message as with `publish`, and return a report of how many subscriptions matched the topic and how
+    /// many of them buffered or dropped the message
+    pub async fn publish_with_report(&self, payload: TopicPayload<T, M>) -> Result<DeliveryReport, PubSubError> {
+        future::poll_fn(|cx| self.dispatcher.poll_ready_for(payload.topic(), cx)).await?;
+        self.dispatcher.publish(payload.with_publisher_id(self.id))
+    }
+
+    /// Publish a request as with `publish`, and return the number of `Responder`s that buffered it
+    pub(crate) async fn publish_request(&self, payload: TopicPayload<T, M>) -> Result<usize, PubSubError> {
+        future::poll_fn(|cx| self.dispatcher.poll_ready_for(payload.topic(), cx)).await?;
+        self.dispatcher
+            .publish_counting_responders(payload.with_publisher_id(self.id))
+            .map(|(_, responders)| responders)
+    }
+
+    /// Publish a message that is only constructed if at least one live subscription is interested in the topic, which
+    /// avoids building expensive messages that nobody will receive. Fails with `PubSubError::NoSubscribers`, without
+    /// calling `build`, if there is no interest in the topic. Because the message is never built in that case it is
+    /// also not kept to backfill subscriptions created later.
+    pub async fn publish_with<F>(&self, topic: T, build: F) -> Result<(), PubSubError>
+    where F: FnOnce() -> M {
+        future::poll_fn(|cx| self.dispatcher.poll_ready_for(&topic, cx)).await?;
+        if !self.dispatcher.has_subscribers(&topic) {
+            return Err(PubSubError::NoSubscribers);
+        }
+        self.dispatcher
+            .publish(TopicPayload::new(topic, build()).with_publisher_id(self.id))
+            .map(|_| ())
+    }
+
+    /// Returns true if at least one live subscription is interested in the topic
+    pub fn has_subscribers(&self, topic: &T) -> bool {
+        self.dispatcher.has_subscribers(topic)
+    }
+
+    /// Publish a message without waiting. Fails with `PubSubError::BufferFull` if a subscription of the message's topic
+    /// with the `Block` overflow policy has a full buffer.
+    pub fn try_publish(&self, payload: TopicPayload<T, M>) -> Result<(), PubSubError> {
+        self.dispatcher
+            .try_publish(payload.with_publisher_id(self.id))
+            .map(|_| ())
+    }
+
+    /// Close the channel for every publisher handle. Subscriptions yield the messages they have already buffered and
+    /// then end, and further attempts to publish fail with `PubSubError::ChannelClosed`.
+    pub fn close(&self) {
+        self.dispatcher.close();
+    }
+
+    /// Shut the channel down gracefully. The channel is closed immediately, so further attempts to publish fail with
+    /// `PubSubError::ChannelClosed`, while subscriptions continue to yield the messages they have already buffered.
+    /// The returned future resolves once every subscription has drained its buffer or been dropped, or fails with
+    /// `PubSubError::Timeout` if that has not happened within `timeout`.
+    pub fn shutdown(&self, timeout: Duration) -> impl Future<Output = Result<(), PubSubError>> {
+        self.dispatcher.close();
+        let dispatcher = self.dispatcher.clone();
+        let drained = future::poll_fn(move |cx| dispatcher.poll_drained(cx));
+        future::select(drained, Delay::new(timeout)).map(|either| match either {
+            Either::Left(_) => Ok(()),
+            Either::Right(_) => Err(PubSubError::Timeout),
+        })
+    }
+
+    /// Remove the retained message of the given topic so that it is no longer delivered to new subscriptions, even if
+    /// it is among the recent messages used to backfill them. Other recent messages of the topic are still delivered.
+    pub fn clear_retained(&self, topic: &T) {
+        self.dispatcher.clear_retained(topic);
+    }
+}
+
+impl<T, M> Sink<TopicPayload<T, M>> for TopicPublisher<T, M>
+where T: Hash + Eq + Clone
+{
+    type Error = PubSubError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.dispatcher.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: TopicPayload<T, M>) -> Result<(), Self::Error> {
+        self.dispatcher.publish(item.with_publisher_id(self.id)).map(|_| ())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, M> Clone for TopicPublisher<T, M>
+where T: Hash + Eq + Clone
+{
+    fn clone(&self) -> Self {
+        Self::new(self.dispatcher.clone())
+    }
+}
+
+impl<T, M> Drop for TopicPublisher<T, M>
+where T: Hash + Eq + Clone
+{
+    fn drop(&mut self) {
+        self.dispatcher.remove_publisher();
+    }
+}
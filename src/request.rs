@@ -0,0 +1,315 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! Request/reply messaging over the topics of a pub-sub channel. A request is published with a unique correlation id
+//! and the topic that replies should be published to, and the reply is matched to the request by its correlation id.
+//! A request can also be scattered to several responders and their replies gathered as a stream.
+
+use std::{
+    hash::Hash,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    future::{self, Either},
+    ready,
+    Future,
+    Stream,
+    StreamExt,
+};
+use futures_timer::Delay;
+
+use crate::{
+    dispatcher::{Dispatcher, Filter, Receiver, SubscriptionSpec},
+    Lagged,
+    OverflowPolicy,
+    PubSubError,
+    TopicPayload,
+    TopicPublisher,
+    TopicSubscriptionFactory,
+};
+
+/// Sends requests over a pub-sub channel and waits for their replies. Replies are published to the requester's reply
+/// topic, which may be shared with other requesters since each reply is matched to its request by correlation id.
+///
+/// A request that is abandoned, either because it timed out or because its future was dropped, stops listening for
+/// its reply, so a late reply is discarded.
+pub struct Requester<T, M>
+where T: Hash + Eq + Clone
+{
+    publisher: TopicPublisher<T, M>,
+    dispatcher: Arc<Dispatcher<T, M>>,
+    reply_topic: T,
+    timeout: Duration,
+    next_request: AtomicU64,
+}
+
+impl<T, M> Requester<T, M>
+where
+    T: Hash + Eq + Clone + Send,
+    M: Clone + Send,
+{
+    /// Create a requester that publishes requests with `publisher`, receives replies on `reply_topic` and gives up
+    /// waiting for a reply after `timeout`
+    pub fn new(
+        publisher: TopicPublisher<T, M>,
+        subscriber_factory: &TopicSubscriptionFactory<T, M>,
+        reply_topic: T,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            publisher,
+            dispatcher: subscriber_factory.dispatcher.clone(),
+            reply_topic,
+            timeout,
+            next_request: AtomicU64::new(0),
+        }
+    }
+
+    /// Publish a request to `topic` and wait for its reply. Fails with `PubSubError::Timeout` if no reply arrives
+    /// within the requester's timeout, or with the error of publishing the request.
+    pub async fn request(&self, topic: T, message: M) -> Result<M, PubSubError> {
+        let correlation_id = self.next_correlation_id();
+        let mut replies = self.subscribe_replies(correlation_id.clone(), Some(1));
+        let request = TopicPayload::new(topic, message)
+            .with_correlation_id(correlation_id)
+            .with_reply_to(self.reply_topic.clone());
+
+        let exchange = async {
+            self.publisher.publish(request).await?;
+            loop {
+                match replies.next().await {
+                    Some(Ok(reply)) => return Ok((**reply.message()).clone()),
+                    Some(Err(_lagged)) => continue,
+                    None => return Err(PubSubError::ChannelClosed),
+                }
+            }
+        };
+        futures::pin_mut!(exchange);
+        match future::select(exchange, Delay::new(self.timeout)).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(PubSubError::Timeout),
+        }
+    }
+
+    /// Publish a request to `topic` once and return a stream of the replies from every responder. The stream ends
+    /// when the first of the following happens: every `Responder` that received the request has replied, the
+    /// maximum number of replies in `limits` has been received, or the deadline in `limits` (by default the
+    /// requester's timeout) has passed. Other subscriptions to `topic` may reply too, but they are only waited for
+    /// when no `Responder` received the request, in which case gathering ends at the maximum or the deadline. Fails
+    /// with the error of publishing the request.
+    pub async fn scatter(&self, topic: T, message: M, limits: GatherLimits) -> Result<Replies<T, M>, PubSubError> {
+        let correlation_id = self.next_correlation_id();
+        let replies = self.subscribe_replies(correlation_id.clone(), None);
+        let request = TopicPayload::new(topic, message)
+            .with_correlation_id(correlation_id)
+            .with_reply_to(self.reply_topic.clone());
+        let responders = self.publisher.publish_request(request).await?;
+        Ok(Replies {
+            replies,
+            outstanding: Some(responders).filter(|n| *n > 0),
+            remaining: limits.max_replies.unwrap_or(usize::MAX),
+            deadline: Delay::new(limits.timeout.unwrap_or(self.timeout)),
+        })
+    }
+
+    fn next_correlation_id(&self) -> String {
+        let request = self.next_request.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{}", self.publisher.id(), request)
+    }
+
+    /// Subscribe to the replies to a single request, the subscription ends when it is dropped. Replies that do not fit
+    /// in its buffer are dropped regardless of the channel's overflow policy, so that they are reported as missed
+    /// rather than rejected, and so that a requester that is slow to gather replies never holds back responders.
+    fn subscribe_replies(&self, correlation_id: String, capacity: Option<usize>) -> Receiver<T, M> {
+        let spec = SubscriptionSpec::new(Filter::topic(self.reply_topic.clone()))
+            .with_overflow_policy(OverflowPolicy::DropNewest)
+            .with_predicate(Box::new(move |reply: &TopicPayload<T, Arc<M>>| {
+                reply.metadata().correlation_id() == Some(correlation_id.as_str())
+            }))
+            .internal()
+            .without_backfill();
+        self.dispatcher.subscribe(match capacity {
+            Some(capacity) => spec.with_capacity(capacity),
+            None => spec,
+        })
+    }
+}
+
+/// Bounds on the replies gathered for a request scattered with `Requester::scatter`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GatherLimits {
+    max_replies: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl GatherLimits {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Stop gathering once `max_replies` replies have been received
+    pub fn with_max_replies(mut self, max_replies: usize) -> Self {
+        self.max_replies = Some(max_replies);
+        self
+    }
+
+    /// Stop gathering once `timeout` has passed, instead of after the requester's timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// The stream of replies to a scattered request, returned by `Requester::scatter`. Replies that arrive after the
+/// stream has ended, or after it was dropped, are discarded.
+pub struct Replies<T, M>
+where T: Hash + Eq + Clone
+{
+    replies: Receiver<T, M>,
+    /// The number of responders whose reply has been neither received nor dropped because the replies fell behind, or
+    /// `None` if the request was not received by any `Responder`, in which case replies are gathered until the limits
+    /// are reached
+    outstanding: Option<usize>,
+    /// The number of replies that may still be yielded
+    remaining: usize,
+    deadline: Delay,
+}
+
+impl<T, M> Replies<T, M>
+where T: Hash + Eq + Clone
+{
+    /// Stop listening for replies once gathering has ended
+    fn finish(&mut self) {
+        self.remaining = 0;
+        self.replies.unsubscribe();
+    }
+}
+
+impl<T, M> Stream for Replies<T, M>
+where
+    T: Hash + Eq + Clone,
+    M: Clone,
+{
+    type Item = M;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.remaining == 0 || self.outstanding == Some(0) || Pin::new(&mut self.deadline).poll(cx).is_ready() {
+            self.finish();
+            return Poll::Ready(None);
+        }
+        loop {
+            match ready!(Pin::new(&mut self.replies).poll_next(cx)) {
+                Some(Ok(reply)) => {
+                    self.remaining -= 1;
+                    self.outstanding = self.outstanding.map(|n| n.saturating_sub(1));
+                    if self.remaining == 0 || self.outstanding == Some(0) {
+                        self.finish();
+                    }
+                    return Poll::Ready(Some((**reply.message()).clone()));
+                },
+                // Replies that were dropped will never arrive, so they are no longer waited for
+                Some(Err(Lagged { missed })) => {
+                    self.outstanding = self.outstanding.map(|n| n.saturating_sub(missed as usize));
+                    if self.outstanding == Some(0) {
+                        self.finish();
+                        return Poll::Ready(None);
+                    }
+                },
+                None => {
+                    self.finish();
+                    return Poll::Ready(None);
+                },
+            }
+        }
+    }
+}
+
+/// Serves the requests published to a topic by replying to each of them. Like any publisher handle, the responder's
+/// publisher keeps the channel open, so it serves requests until the channel is closed explicitly.
+///
+/// A responder only serves the requests published after it was created. Earlier requests are not backfilled, as their
+/// requesters may have given up waiting for a reply long ago.
+pub struct Responder<T, M>
+where T: Hash + Eq + Clone
+{
+    publisher: TopicPublisher<T, M>,
+    requests: Receiver<T, M>,
+}
+
+impl<T, M> Responder<T, M>
+where
+    T: Hash + Eq + Clone + Send,
+    M: Clone + Send,
+{
+    /// Create a responder for the requests published to `topic`, which publishes its replies with `publisher`
+    pub fn new(publisher: TopicPublisher<T, M>, subscriber_factory: &TopicSubscriptionFactory<T, M>, topic: T) -> Self {
+        Self {
+            publisher,
+            requests: subscriber_factory.dispatcher.subscribe(
+                SubscriptionSpec::new(Filter::topic(topic))
+                    .without_backfill()
+                    .responder(),
+            ),
+        }
+    }
+
+    /// Reply to each request with the message returned by `handler`, until the channel is closed. Messages published
+    /// to the topic without a reply topic or correlation id are not requests and are ignored. A reply that cannot be
+    /// published, e.g. because it was rejected by a full subscription with the `Fail` overflow policy, is discarded and
+    /// the responder goes on serving the following requests.
+    pub async fn run<F, Fut>(mut self, mut handler: F)
+    where
+        F: FnMut(M) -> Fut,
+        Fut: Future<Output = M>,
+    {
+        while let Some(request) = self.requests.next().await {
+            let request = match request {
+                Ok(request) => request,
+                Err(_lagged) => continue,
+            };
+            let (reply_to, correlation_id) = match (request.reply_to(), request.metadata().correlation_id()) {
+                (Some(reply_to), Some(correlation_id)) => (reply_to.clone(), correlation_id.to_string()),
+                _ => continue,
+            };
+            let reply = handler((**request.message()).clone()).await;
+            let reply = TopicPayload::new(reply_to, reply).with_correlation_id(correlation_id);
+            match self.publisher.publish(reply).await {
+                Ok(()) | Err(PubSubError::ChannelClosed) => {},
+                #[cfg(feature = "tracing")]
+                Err(err) => tracing::debug!(
+                    subscription_id = %self.requests.id(),
+                    correlation_id = request.metadata().correlation_id(),
+                    "Reply discarded: {}",
+                    err
+                ),
+                #[cfg(not(feature = "tracing"))]
+                Err(_) => {},
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use std::collections::HashMap;
+
+use crate::SubscriptionId;
+
+/// Counters for the messages published to a single topic
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TopicStats {
+    /// The number of messages published to the topic
+    pub published: u64,
+    /// The number of messages of the topic that were yielded to subscriptions
+    pub delivered: u64,
+    /// The number of messages of the topic that were dropped from, or never buffered by, a full subscription
+    pub dropped: u64,
+}
+
+/// Counters and buffer usage of a single subscription
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionStats<T> {
+    pub id: SubscriptionId,
+    /// The topics the subscription receives messages for, empty for subscriptions that match topics by pattern or
+    /// predicate
+    pub topics: Vec<T>,
+    /// The number of messages the subscription has yielded
+    pub delivered: u64,
+    /// The number of messages that were dropped from, or never buffered by, the subscription because its buffer was
+    /// full
+    pub dropped: u64,
+    /// The number of messages currently buffered for the subscription
+    pub queue_depth: usize,
+    /// The largest number of messages that were buffered for the subscription at once, i.e. the furthest it has
+    /// fallen behind the publisher
+    pub max_queue_depth: usize,
+}
+
+/// A snapshot of the counters of a pub-sub channel, returned by `TopicSubscriptionFactory::stats`
+#[derive(Debug, Clone)]
+pub struct ChannelStats<T> {
+    /// The receiver id of the channel
+    pub receiver_id: usize,
+    /// The counters of every topic that has been published to since the channel was created or its counters were
+    /// reset. This is empty if the channel was configured not to count messages for each topic.
+    pub topics: HashMap<T, TopicStats>,
+    /// The counters of every live subscription, ordered by id. The short-lived subscriptions that receive the replies
+    /// to a `Requester`'s requests are not included.
+    pub subscriptions: Vec<SubscriptionStats<T>>,
+}
@@ -0,0 +1,175 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use std::{
+    hash::Hash,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Stream};
+
+use crate::dispatcher::{ReceivedItem, Receiver, SubscriptionId};
+
+/// A handle to a single subscription that yields the messages routed to it. The subscription is registered with its
+/// channel for as long as the handle exists, and is deregistered when the handle is dropped so that the channel stops
+/// routing messages to it.
+///
+/// Subscriptions yield the messages themselves by default. The item type `I` differs for subscriptions that yield the
+/// shared `Arc<M>`, the full `TopicPayload`, or report the gaps left by dropped messages as `Lagged` errors.
+pub struct Subscription<T, M, I = M>
+where T: Hash + Eq + Clone
+{
+    receiver: Receiver<T, M>,
+    map: fn(ReceivedItem<T, M>) -> Option<I>,
+}
+
+impl<T, M> Subscription<T, M>
+where
+    T: Hash + Eq + Clone,
+    M: Clone,
+{
+    pub(crate) fn new(receiver: Receiver<T, M>) -> Self {
+        Self::with_map(receiver, |item| item.ok().map(|item| (**item.message()).clone()))
+    }
+}
+
+impl<T, M, I> Subscription<T, M, I>
+where T: Hash + Eq + Clone
+{
+    /// Create a subscription that converts each received item using `map`, skipping the items for which it returns
+    /// `None`
+    pub(crate) fn with_map(receiver: Receiver<T, M>, map: fn(ReceivedItem<T, M>) -> Option<I>) -> Self {
+        Self { receiver, map }
+    }
+
+    /// Returns the id that identifies this subscription within its channel
+    pub fn id(&self) -> SubscriptionId {
+        self.receiver.id()
+    }
+
+    /// Returns the topics this subscription receives messages for. This is empty for subscriptions that match topics
+    /// by pattern or predicate, and once the subscription has been unsubscribed.
+    pub fn topics(&self) -> Vec<T> {
+        self.receiver.topics()
+    }
+
+    /// Stop receiving newly published messages. Messages that are already buffered are still yielded, after which the
+    /// stream ends. Returns false if the subscription was already unsubscribed.
+    pub fn unsubscribe(&self) -> bool {
+        self.receiver.unsubscribe()
+    }
+
+    /// Returns the span the most recently yielded message was delivered in. It is a child of the span that was current
+    /// when the message was published, so entering it while handling the message traces the handling as part of the
+    /// publisher's trace. Messages that were backfilled when the subscription was created are delivered in a span
+    /// without a parent.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        self.receiver.span()
+    }
+}
+
+impl<T, M, I> Stream for Subscription<T, M, I>
+where T: Hash + Eq + Clone
+{
+    type Item = I;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.receiver).poll_next(cx)) {
+                Some(item) => {
+                    if let Some(item) = (self.map)(item) {
+                        return Poll::Ready(Some(item));
+                    }
+                },
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// A subscription whose set of topics can be changed while it is live. Adding or removing a topic takes effect
+/// atomically with respect to publishing, and messages that are already buffered for the subscription are kept.
+pub struct DynamicSubscription<T, M>
+where T: Hash + Eq + Clone
+{
+    receiver: Receiver<T, M>,
+}
+
+impl<T, M> DynamicSubscription<T, M>
+where T: Hash + Eq + Clone
+{
+    pub(crate) fn new(receiver: Receiver<T, M>) -> Self {
+        Self { receiver }
+    }
+
+    /// Returns the id that identifies this subscription within its channel
+    pub fn id(&self) -> SubscriptionId {
+        self.receiver.id()
+    }
+
+    /// Start receiving messages published to `topic`. Returns false if the subscription already included the topic.
+    pub fn add_topic(&self, topic: T) -> bool {
+        self.receiver.add_topic(topic)
+    }
+
+    /// Stop receiving messages published to `topic`. Messages of the topic that are already buffered will still be
+    /// yielded. Returns false if the subscription did not include the topic.
+    pub fn remove_topic(&self, topic: &T) -> bool {
+        self.receiver.remove_topic(topic)
+    }
+
+    /// Stop receiving newly published messages of every topic. Messages that are already buffered are still yielded,
+    /// after which the stream ends. Returns false if the subscription was already unsubscribed.
+    pub fn unsubscribe(&self) -> bool {
+        self.receiver.unsubscribe()
+    }
+
+    /// Returns the topics this subscription currently receives messages for
+    pub fn topics(&self) -> Vec<T> {
+        self.receiver.topics()
+    }
+
+    /// Returns the span the most recently yielded message was delivered in, as with `Subscription::span`
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        self.receiver.span()
+    }
+}
+
+impl<T, M> Stream for DynamicSubscription<T, M>
+where
+    T: Hash + Eq + Clone,
+    M: Clone,
+{
+    type Item = M;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.receiver).poll_next(cx)) {
+                Some(Ok(item)) => return Poll::Ready(Some((**item.message()).clone())),
+                Some(Err(_lagged)) => continue,
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2019. The Tari Project
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+// following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+// disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+// following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+// products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+// INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use std::{error::Error, fmt, str::FromStr};
+
+/// The character used to separate the levels of a hierarchical topic
+pub const TOPIC_SEPARATOR: char = '/';
+/// Wildcard that matches exactly one topic level
+pub const SINGLE_LEVEL_WILDCARD: &str = "+";
+/// Wildcard that matches any number of trailing topic levels, including none
+pub const MULTI_LEVEL_WILDCARD: &str = "#";
+
+/// Errors that can occur when parsing a hierarchical topic or topic pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicError {
+    /// The topic or pattern was an empty string
+    Empty,
+    /// A concrete topic contained a wildcard character
+    WildcardInTopic(String),
+    /// A wildcard was used in a position where it is not allowed, e.g. `a/b#` or `#/a`
+    InvalidWildcard(String),
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicError::Empty => write!(f, "Topic cannot be empty"),
+            TopicError::WildcardInTopic(topic) => write!(f, "Topic '{}' cannot contain wildcards", topic),
+            TopicError::InvalidWildcard(pattern) => write!(f, "Topic pattern '{}' has a misplaced wildcard", pattern),
+        }
+    }
+}
+
+impl Error for TopicError {}
+
+/// A concrete topic made up of levels separated by `/`, e.g. `wallet/1/balance`. Wildcards are not permitted in a
+/// concrete topic; use a [TopicPattern] to subscribe to more than one topic.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HierarchicalTopic(String);
+
+impl HierarchicalTopic {
+    pub fn new<S: Into<String>>(topic: S) -> Result<Self, TopicError> {
+        let topic = topic.into();
+        if topic.is_empty() {
+            return Err(TopicError::Empty);
+        }
+        if topic.contains(SINGLE_LEVEL_WILDCARD) || topic.contains(MULTI_LEVEL_WILDCARD) {
+            return Err(TopicError::WildcardInTopic(topic));
+        }
+        Ok(Self(topic))
+    }
+
+    /// Returns an iterator over the levels of this topic
+    pub fn levels(&self) -> impl Iterator<Item = &str> {
+        self.0.split(TOPIC_SEPARATOR)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for HierarchicalTopic {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for HierarchicalTopic {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl fmt::Display for HierarchicalTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PatternLevel {
+    Exact(String),
+    SingleLevel,
+    MultiLevel,
+}
+
+/// An MQTT-style topic filter that matches one or more hierarchical topics.
+///
+/// `+` matches exactly one level and `#`, which may only appear as the final level, matches the parent level and any
+/// number of levels below it. For example `wallet/+/balance` matches `wallet/1/balance` but not `wallet/1/2/balance`,
+/// and `wallet/#` matches `wallet`, `wallet/1` and `wallet/1/balance`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicPattern {
+    pattern: String,
+    levels: Vec<PatternLevel>,
+}
+
+impl TopicPattern {
+    pub fn new<S: Into<String>>(pattern: S) -> Result<Self, TopicError> {
+        let pattern = pattern.into();
+        if pattern.is_empty() {
+            return Err(TopicError::Empty);
+        }
+
+        let mut levels = Vec::new();
+        let mut iter = pattern.split(TOPIC_SEPARATOR).peekable();
+        while let Some(level) = iter.next() {
+            let level = match level {
+                SINGLE_LEVEL_WILDCARD => PatternLevel::SingleLevel,
+                MULTI_LEVEL_WILDCARD if iter.peek().is_none() => PatternLevel::MultiLevel,
+                l if l.contains(SINGLE_LEVEL_WILDCARD) || l.contains(MULTI_LEVEL_WILDCARD) => {
+                    return Err(TopicError::InvalidWildcard(pattern));
+                },
+                l => PatternLevel::Exact(l.to_string()),
+            };
+            levels.push(level);
+        }
+
+        Ok(Self { pattern, levels })
+    }
+
+    /// Returns true if this pattern contains a wildcard and can therefore match more than one topic
+    pub fn is_wildcard(&self) -> bool {
+        self.levels.iter().any(|l| !matches!(l, PatternLevel::Exact(_)))
+    }
+
+    /// Returns true if the given topic is matched by this pattern
+    pub fn matches<S: AsRef<str> + ?Sized>(&self, topic: &S) -> bool {
+        let mut topic_levels = topic.as_ref().split(TOPIC_SEPARATOR);
+        for level in &self.levels {
+            match level {
+                PatternLevel::MultiLevel => return true,
+                PatternLevel::SingleLevel => {
+                    if topic_levels.next().is_none() {
+                        return false;
+                    }
+                },
+                PatternLevel::Exact(expected) => match topic_levels.next() {
+                    Some(l) if l == expected => {},
+                    _ => return false,
+                },
+            }
+        }
+        topic_levels.next().is_none()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+}
+
+impl FromStr for TopicPattern {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl fmt::Display for TopicPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pattern(s: &str) -> TopicPattern {
+        TopicPattern::new(s).unwrap()
+    }
+
+    #[test]
+    fn topic_validation() {
+        assert_eq!(HierarchicalTopic::new(""), Err(TopicError::Empty));
+        assert!(HierarchicalTopic::new("wallet/+/balance").is_err());
+        assert!(HierarchicalTopic::new("wallet/#").is_err());
+
+        let topic = HierarchicalTopic::new("wallet/1/balance").unwrap();
+        assert_eq!(topic.levels().collect::<Vec<_>>(), vec!["wallet", "1", "balance"]);
+        assert_eq!(topic.to_string(), "wallet/1/balance");
+    }
+
+    #[test]
+    fn pattern_validation() {
+        assert_eq!(TopicPattern::new(""), Err(TopicError::Empty));
+        assert!(TopicPattern::new("wallet/#/balance").is_err());
+        assert!(TopicPattern::new("wallet/b#").is_err());
+        assert!(TopicPattern::new("wallet/a+/balance").is_err());
+        assert!(!pattern("wallet/1/balance").is_wildcard());
+        assert!(pattern("wallet/+/balance").is_wildcard());
+        assert!(pattern("#").is_wildcard());
+    }
+
+    #[test]
+    fn single_level_wildcard() {
+        let p = pattern("wallet/+/balance");
+        assert!(p.matches("wallet/1/balance"));
+        assert!(p.matches("wallet//balance"));
+        assert!(!p.matches("wallet/1/2/balance"));
+        assert!(!p.matches("wallet/balance"));
+        assert!(!p.matches("wallet/1/balances"));
+
+        let p = pattern("+");
+        assert!(p.matches("wallet"));
+        assert!(!p.matches("wallet/1"));
+    }
+
+    #[test]
+    fn multi_level_wildcard() {
+        let p = pattern("wallet/#");
+        assert!(p.matches("wallet"));
+        assert!(p.matches("wallet/1"));
+        assert!(p.matches("wallet/1/balance"));
+        assert!(!p.matches("wallets/1"));
+
+        let p = pattern("wallet/+/#");
+        assert!(p.matches("wallet/1"));
+        assert!(p.matches("wallet/1/balance/pending"));
+        assert!(!p.matches("wallet"));
+
+        assert!(pattern("#").matches("anything/at/all"));
+    }
+
+    #[test]
+    fn exact_pattern() {
+        let p = pattern("wallet/1/balance");
+        assert!(p.matches(&HierarchicalTopic::new("wallet/1/balance").unwrap()));
+        assert!(!p.matches("wallet/1"));
+        assert!(!p.matches("wallet/1/balance/pending"));
+    }
+}